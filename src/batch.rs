@@ -0,0 +1,274 @@
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+
+#[allow(unused)]
+use log::{info, warn, error, debug, trace};
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tokio_util::sync::CancellationToken;
+
+use tftp::client::{TftpClient, TftpRequestParameters};
+use tftp::options::TftpOption;
+use tftp::stats::TransferStats;
+use tftp::{Mode, RequestKind};
+
+use crate::cli;
+use crate::target::{self, ServerTarget};
+
+/// Retries per transfer unless `--retries` is given.
+const DEFAULT_RETRIES: u32 = 2;
+
+///
+/// A transfer read from a manifest. Lines look like
+///
+/// ```text
+/// # direction  server       files...                options...
+/// get          192.0.2.1    pxelinux.cfg/default    boot/default  blksize=1428
+/// put          host:6969    dev1.log                logs/dev1.log tsize=yes
+/// get          tftp://host/pxelinux.0               mode=octet
+/// ```
+///
+/// Files are given as on the command line: `REMOTE [LOCAL]` for get, `LOCAL [REMOTE]`
+/// for put, or only the local one if the server is a URI naming the file. Everything
+/// containing a `=` after that is an option: `blksize`, `timeout`, `tsize`, `windowsize`,
+/// `mode` or any other option to request as is.
+///
+#[derive(Debug, Clone)]
+pub struct ManifestEntry {
+	pub line: usize,
+	pub kind: RequestKind,
+	pub server: ServerTarget,
+	pub remote: String,
+	pub local: PathBuf,
+	pub mode: Mode,
+	pub timeout: Option<Duration>,
+	/// In addition to and replacing those given on the command line.
+	pub options: Vec<TftpOption>,
+}
+
+pub fn parse_manifest(text: &str) -> Result<Vec<ManifestEntry>, String> {
+	text.lines()
+		.enumerate()
+		.map(|(idx, line)| (idx + 1, line.split('#').next().unwrap_or("").trim()))
+		.filter(|(_, line)| !line.is_empty())
+		.map(|(nr, line)| parse_entry(nr, line).map_err(|e| format!("line {}: {}", nr, e)))
+		.collect()
+}
+
+fn parse_entry(nr: usize, line: &str) -> Result<ManifestEntry, String> {
+	let mut tokens = line.split_whitespace();
+	let kind = match tokens.next() {
+		Some("get") => RequestKind::Rrq,
+		Some("put") => RequestKind::Wrq,
+		Some(other) => return Err(format!("expected 'get' or 'put', got '{}'", other)),
+		None => unreachable!("empty lines are skipped"),
+	};
+	let server = tokens.next().ok_or("missing server")?.parse::<ServerTarget>()?;
+
+	let (files, options): (Vec<&str>, Vec<&str>) = tokens.partition(|t| !t.contains('='));
+	let (local, remote) = match (kind, server.file.as_ref(), &files[..]) {
+		(RequestKind::Rrq, Some(file), []) => (cli::default_local(file)?, file.clone()),
+		(RequestKind::Rrq, Some(file), [local]) => (PathBuf::from(local), file.clone()),
+		(RequestKind::Rrq, None, [remote]) => (cli::default_local(remote)?, remote.to_string()),
+		(RequestKind::Rrq, None, [remote, local]) => (PathBuf::from(local), remote.to_string()),
+		(RequestKind::Wrq, Some(file), [local]) => (PathBuf::from(local), file.clone()),
+		(RequestKind::Wrq, None, [local]) => (PathBuf::from(local), cli::default_remote(Path::new(local))?),
+		(RequestKind::Wrq, None, [local, remote]) => (PathBuf::from(local), remote.to_string()),
+		(_, _, []) => return Err("missing file".to_string()),
+		_ => return Err("too many files".to_string()),
+	};
+	if cli::is_stdio(&local) {
+		return Err("stdin and stdout can't be used in a manifest".to_string());
+	}
+
+	let mut entry = ManifestEntry {
+		line: nr,
+		kind,
+		mode: server.mode.unwrap_or(Mode::Octet),
+		server,
+		remote,
+		local,
+		timeout: None,
+		options: Vec::new(),
+	};
+	for option in options {
+		let (name, value) = option.split_once('=').unwrap_or((option, ""));
+		match name {
+			"mode" => entry.mode = value.parse::<Mode>().map_err(|_| format!("unknown mode '{}'", value))?,
+			"blksize" => match value.parse::<cli::Blocksize>()? {
+				cli::Blocksize::Fixed(bs) => entry.options.push(TftpOption::Blocksize(bs)),
+				cli::Blocksize::Auto => return Err("blksize=auto is only available on the command line".to_string()),
+			},
+			"timeout" => {
+				let timeout = cli::parse_timeout(value)?;
+				entry.timeout = Some(timeout);
+				entry.options.extend(cli::timeout_options(timeout));
+			},
+			"windowsize" => match value.parse::<u16>() {
+				Ok(ws) if ws >= 1 => entry.options.push(TftpOption::WindowSize(ws)),
+				_ => return Err(format!("invalid windowsize '{}'", value)),
+			},
+			"tsize" => match value {
+				"yes" | "1" => entry.options.push(TftpOption::TransferSize(0)),
+				"no" | "0" => (),
+				_ => return Err(format!("expected tsize=yes or tsize=no, got '{}'", option)),
+			},
+			_ => entry.options.push(cli::parse_custom_option(option)?),
+		}
+	}
+	Ok(entry)
+}
+
+/// Result of one manifest entry, after all attempts.
+struct Outcome {
+	entry: ManifestEntry,
+	attempts: u32,
+	result: Result<TransferStats, String>,
+}
+
+///
+/// Runs the transfers in `manifest` (a file, or `-` for stdin), at most `jobs` at a
+/// time, each retried as the command line's retry policy says. Prints a summary table
+/// and fails if any transfer failed.
+///
+pub async fn run(
+	opts: cli::ClientOpts,
+	manifest: PathBuf,
+	jobs: usize,
+	port: u16,
+	root: PathBuf,
+	cxl_token: CancellationToken,
+) -> io::Result<()> {
+	let text = match cli::is_stdio(&manifest) {
+		true => {
+			let mut text = String::new();
+			io::stdin().read_to_string(&mut text)?;
+			text
+		},
+		false => std::fs::read_to_string(&manifest)?,
+	};
+	let entries = parse_manifest(&text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+	let mut client = TftpClient::new(cxl_token.clone());
+	client.set_dally_period(opts.dally.map(|d| Duration::from_secs(d as u64)));
+	client.set_auto_blocksize(opts.blocksize == cli::Blocksize::Auto);
+	client.set_blocksize_probing(opts.probe_blocksize);
+	client.set_retry_policy(cli::retry_policy(&opts, DEFAULT_RETRIES));
+	let timeout = opts.timeout;
+	cli::parse_tftp_options(opts)
+		.iter()
+		.for_each(|opt| client.add_option(opt));
+	let client = Arc::new(client);
+
+	let permits = Arc::new(Semaphore::new(jobs.max(1)));
+	let mut tasks: JoinSet<Outcome> = JoinSet::new();
+	for entry in entries.iter().cloned() {
+		let (client, permits, root) = (client.clone(), permits.clone(), root.clone());
+		tasks.spawn(async move {
+			let _permit = permits.acquire_owned().await;
+			let (result, attempts) = transfer(&client, &entry, port, &root, timeout).await;
+			match result.as_ref() {
+				Ok(stats) => info!("line {}: '{}' done: {}", entry.line, entry.remote, stats),
+				Err(e) => error!("line {}: '{}' failed: {}", entry.line, entry.remote, e),
+			}
+			Outcome { entry, attempts, result: result.map_err(|e| e.to_string()) }
+		});
+	}
+
+	let mut outcomes: Vec<Outcome> = Vec::with_capacity(entries.len());
+	while let Some(outcome) = tasks.join_next().await {
+		outcomes.push(outcome.map_err(io::Error::other)?);
+	}
+	outcomes.sort_by_key(|o| o.entry.line);
+	print_summary(&outcomes);
+
+	match outcomes.iter().filter(|o| o.result.is_err()).count() {
+		0 => Ok(()),
+		failed => Err(io::Error::other(format!("{} of {} transfers failed", failed, outcomes.len()))),
+	}
+}
+
+/// Returns the attempts made on all addresses tried, none if the server didn't resolve.
+/// `timeout` from the command line applies unless the entry sets its own.
+async fn transfer(client: &TftpClient, entry: &ManifestEntry, port: u16, root: &Path, timeout: Duration) -> (tftp::client::Result<TransferStats>, u32) {
+	let servers = match entry.server.resolve(port).await {
+		Ok(servers) => servers,
+		Err(e) => return (Err(e.into()), 0),
+	};
+	let local = root.join(&entry.local);
+	let attempts = AtomicU32::new(0);
+	let res = target::try_each(servers, |server| {
+		let params = TftpRequestParameters::new(entry.kind, server, local.clone())
+			.remote(&entry.remote)
+			.options(&entry.options)
+			.mode(entry.mode)
+			.timeout(entry.timeout.unwrap_or(timeout));
+		let attempts = &attempts;
+		async move {
+			let (res, n) = client.request_counted(&params).await;
+			attempts.fetch_add(n, Ordering::Relaxed);
+			res
+		}
+	}).await;
+	(res, attempts.into_inner())
+}
+
+fn print_summary(outcomes: &[Outcome]) {
+	let header = ["LINE", "DIR", "SERVER", "REMOTE", "LOCAL", "STATUS", "BYTES", "TIME", "KIB/S", "TRIES", "BLKSIZE"];
+	let rows: Vec<[String; 11]> = outcomes
+		.iter()
+		.map(|o| {
+			let (status, bytes, time, rate, blksize) = match o.result.as_ref() {
+				Ok(stats) => (
+					"ok".to_string(),
+					stats.bytes.to_string(),
+					format!("{:.2}s", stats.duration.as_secs_f64()),
+					format!("{:.1}", stats.throughput() / 1024.0),
+					stats.options.blocksize.to_string(),
+				),
+				Err(_) => ("FAILED".to_string(), "-".into(), "-".into(), "-".into(), "-".into()),
+			};
+			[
+				o.entry.line.to_string(),
+				match o.entry.kind { RequestKind::Rrq => "get", RequestKind::Wrq => "put" }.to_string(),
+				o.entry.server.to_string(),
+				o.entry.remote.clone(),
+				o.entry.local.display().to_string(),
+				status,
+				bytes,
+				time,
+				rate,
+				o.attempts.to_string(),
+				blksize,
+			]
+		})
+		.collect();
+
+	let mut widths = header.map(str::len);
+	for row in rows.iter() {
+		for (width, cell) in widths.iter_mut().zip(row.iter()) {
+			*width = (*width).max(cell.len());
+		}
+	}
+	let print_row = |cells: &[&str]| {
+		let line: Vec<String> = cells
+			.iter()
+			.zip(widths.iter())
+			.map(|(cell, width)| format!("{:<width$}", cell, width = width))
+			.collect();
+		println!("{}", line.join("  ").trim_end());
+	};
+
+	print_row(&header);
+	for row in rows.iter() {
+		print_row(&row.each_ref().map(String::as_str));
+	}
+	for o in outcomes.iter() {
+		if let Err(e) = o.result.as_ref() {
+			println!("line {}: {}", o.entry.line, e);
+		}
+	}
+}
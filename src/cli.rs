@@ -1,14 +1,25 @@
-use std::net::{IpAddr, Ipv4Addr};
+use std::fmt::Display;
+#[cfg(feature = "server")]
+use std::net::{IpAddr, Ipv4Addr, SocketAddrV4};
+use std::str::FromStr;
 use std::path::PathBuf;
+#[cfg(feature = "client")]
+use std::path::Path;
 use std::time::Duration;
 
-use clap::{arg, command, ValueEnum, Args};
+use clap::{ValueEnum, Args};
 use clap::{Parser, Subcommand};
 
 use simple_logger::SimpleLogger;
 
-use crate::tftp;
-use crate::tftp::options::TftpOption;
+#[cfg(feature = "client")]
+use tftp::client::{RetryCondition, RetryPolicy};
+#[cfg(feature = "client")]
+use tftp::error::ErrorCode;
+use tftp::checksum::{Algorithm, Checksum};
+use tftp::options::TftpOption;
+
+use crate::target::ServerTarget;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about)]
@@ -48,24 +59,129 @@ impl From<DebugLevel> for log::LevelFilter {
 	}
 }
 
+/// A blksize given on the command line, either fixed or derived from the MTU of
+/// the network path.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Blocksize {
+	Auto,
+	Fixed(u16),
+}
+impl Display for Blocksize {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::Auto => write!(f, "auto"),
+			Self::Fixed(bs) => write!(f, "{}", bs),
+		}
+	}
+}
+impl FromStr for Blocksize {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		if s.eq_ignore_ascii_case("auto") {
+			return Ok(Self::Auto);
+		}
+		match s.parse::<u16>() {
+			Ok(bs) if (tftp::consts::MIN_BLOCK_SIZE..=tftp::consts::MAX_BLOCK_SIZE).contains(&bs) => Ok(Self::Fixed(bs)),
+			_ => Err(format!(
+				"expected 'auto' or a number between {} and {}",
+				tftp::consts::MIN_BLOCK_SIZE, tftp::consts::MAX_BLOCK_SIZE
+			)),
+		}
+	}
+}
+
 #[derive(Debug, Args)]
 pub struct ClientOpts {
-	#[arg(short, long, default_value_t = crate::tftp::consts::DEFAULT_BLOCK_SIZE)]
-	pub blocksize: u16,
+	#[arg(
+		short, long, default_value_t = Blocksize::Fixed(tftp::consts::DEFAULT_BLOCK_SIZE),
+		help = "Blocksize to request, or 'auto' to derive it from the path MTU."
+	)]
+	pub blocksize: Blocksize,
 
 	#[arg(
-		short, long, default_value_t = crate::tftp::consts::DEFAULT_TIMEOUT_SECS,
-		help = "Timout waiting for packet (in seconds)."
+		long, default_value_t = false,
+		help = "Retry with a smaller blocksize when full-size blocks keep timing out."
 	)]
-	pub timeout: u8,
+	pub probe_blocksize: bool,
+
+	#[arg(
+		short, long, default_value = "5", value_parser = parse_timeout,
+		help = "Timout waiting for packet (in seconds). Fractions like 0.2 are requested via utimeout."
+	)]
+	pub timeout: Duration,
 
 	#[arg(
 		short = 'T', long, default_value_t = false,
 		help = "Request (for RRQ) or hand over (for WRQ) the size of the file."
 	)]
 	pub transfer_size: bool,
+
+	#[arg(
+		long, value_name = "N", value_parser = clap::value_parser!(u16).range(1..),
+		help = "Request a windowsize (RFC 7440): blocks sent before waiting for an ACK."
+	)]
+	pub windowsize: Option<u16>,
+
+	#[arg(
+		long, default_value_t = false,
+		help = "Join a multicast session (RFC 2090) if the server offers one. Downloads only."
+	)]
+	pub multicast: bool,
+
+	#[arg(
+		long,
+		help = "Time to linger after the final ACK of a download (in seconds), in case the server didn't get it. Off by default."
+	)]
+	pub dally: Option<u8>,
+
+	#[arg(
+		long, value_name = "N",
+		help = "How often to start a failed transfer over. Only transfers from and to files are retried."
+	)]
+	pub retries: Option<u32>,
+
+	#[arg(
+		long, value_name = "SECS", default_value = "1", value_parser = parse_backoff,
+		help = "Pause before the first retry (in seconds), doubled with each further one up to 30s."
+	)]
+	pub retry_backoff: Duration,
+
+	#[cfg(feature = "client")]
+	#[arg(
+		long, value_name = "CONDITION", value_delimiter = ',', value_parser = parse_retry_condition,
+		help = "Failures worth a retry: timeout, no-response, unknown-tid, io, busy, error=CODE or message=TEXT. \
+			Defaults to timeout,no-response,unknown-tid,busy."
+	)]
+	pub retry_on: Vec<RetryCondition>,
+
+	#[arg(
+		short = 'o', long = "option", value_name = "NAME=VALUE", value_parser = parse_custom_option,
+		help = "Request an additional option, e.g. a vendor option. Can be given multiple times."
+	)]
+	pub custom_options: Vec<TftpOption>,
+
+	#[arg(
+		long, default_value_t = false,
+		help = "Read all of stdin before uploading it with 'put -', so its size is known and tsize can be sent."
+	)]
+	pub buffer_stdin: bool,
+
+	#[arg(
+		short, long, default_value_t = false,
+		help = "Don't show the progress of the transfer."
+	)]
+	pub quiet: bool,
+
+	#[arg(
+		long, value_enum,
+		help = "How to show the progress. Defaults to a bar if stderr is a terminal."
+	)]
+	pub progress: Option<crate::progress::ProgressMode>,
 }
 
+/* Parsed once, the size of the variants doesn't matter */
+#[allow(clippy::large_enum_variant)]
 #[derive(Subcommand, Debug)]
 pub enum RunMode {
 	#[cfg(feature = "server")]
@@ -73,8 +189,32 @@ pub enum RunMode {
 		#[arg(short, long, default_value_t = IpAddr::V4(Ipv4Addr::UNSPECIFIED))]
 		bind: IpAddr,
 
-		#[arg(short, long, default_value_t = crate::tftp::consts::TFTP_LISTEN_PORT)]
+		#[arg(short, long, default_value_t = tftp::consts::TFTP_LISTEN_PORT)]
 		port: u16,
+
+		#[arg(
+			long,
+			help = "Time to linger after the final ACK of an upload (in seconds). Defaults to the timeout, 0 disables it."
+		)]
+		dally: Option<u8>,
+
+		#[arg(
+			long, default_value_t = tftp::consts::MIN_BLOCK_SIZE,
+			help = "Smallest blksize granted to clients; smaller requests are answered without blksize."
+		)]
+		min_blocksize: u16,
+
+		#[arg(
+			long, default_value_t = Blocksize::Auto,
+			help = "Largest blksize granted to clients; larger requests are lowered to it. 'auto' uses the path MTU."
+		)]
+		max_blocksize: Blocksize,
+
+		#[arg(
+			long, value_name = "GROUP:PORT",
+			help = "Send files to clients asking for it via multicast (RFC 2090), starting at this group."
+		)]
+		multicast: Option<SocketAddrV4>,
 	},
 	#[cfg(feature = "client")]
 	Client {
@@ -88,45 +228,207 @@ pub enum RunMode {
 
 #[derive(Debug, Args)]
 pub struct ClientActionOpts {
-	pub file: PathBuf,
-
-	#[arg(help = "The remote server to connect to.")]
-	pub server: IpAddr,
+	#[arg(
+		value_name = "SERVER|URI",
+		help = "The remote server to connect to: a host name or address, optionally with :port, or a tftp://host[:port]/file[;mode=octet] URI which names the file as well."
+	)]
+	pub server: ServerTarget,
 
 	#[arg(
-		default_value_t = crate::tftp::consts::TFTP_LISTEN_PORT,
-		help = "(optional) The remote port to connect to."
+		short, long, default_value_t = tftp::consts::TFTP_LISTEN_PORT,
+		help = "The remote port to connect to, unless SERVER has one."
 	)]
 	pub port: u16,
+
+	#[arg(
+		long, value_name = "ALGORITHM",
+		help = "Print the sha256, md5 or crc32 digest of the data transferred, like sha256sum does."
+	)]
+	pub digest: Option<Algorithm>,
 }
 
 #[derive(Subcommand, Debug)]
 pub enum ClientAction {
+	/// Download REMOTE from the server and save it as LOCAL.
 	Get {
 		#[command(flatten)]
 		opts: ClientActionOpts,
+
+		#[arg(help = "Name of the file on the server, sent as is, including directories. Taken from the URI if there is one.")]
+		remote: Option<String>,
+
+		#[arg(help = "Where to save the file, relative to the root directory, or '-' for stdout. Defaults to the last component of REMOTE.")]
+		local: Option<PathBuf>,
+
+		#[arg(
+			long, value_name = "ALGORITHM:HEX", conflicts_with = "sidecar",
+			help = "Expected sha256, md5 or crc32 digest of the file, e.g. crc32:cbf43926. LOCAL is only replaced if it matches."
+		)]
+		checksum: Option<Checksum>,
+
+		#[arg(
+			long, value_name = "ALGORITHM",
+			help = "Fetch the expected digest from REMOTE.sha256, .md5 or .crc32 on the same server first."
+		)]
+		sidecar: Option<Algorithm>,
 	},
+	/// Upload LOCAL to the server as REMOTE.
 	Put {
 		#[command(flatten)]
 		opts: ClientActionOpts,
-	}
+
+		#[arg(help = "The file to upload, relative to the root directory, or '-' for stdin.")]
+		local: PathBuf,
+
+		#[arg(help = "Name of the file on the server, sent as is. Defaults to the file in the URI or the file name of LOCAL.")]
+		remote: Option<String>,
+	},
+	/// Copy a file from one server to another, without storing it locally.
+	Relay {
+		#[arg(
+			value_name = "FROM",
+			help = "The server to download from, a host name or address, optionally with :port, or a tftp:// URI naming the file."
+		)]
+		from: ServerTarget,
+
+		#[arg(value_name = "TO", help = "The server to upload to, or a tftp:// URI to rename the file.")]
+		to: ServerTarget,
+
+		#[arg(help = "Name of the file on the source server, unless FROM is a URI. It keeps its name unless TO names another.")]
+		remote: Option<String>,
+
+		#[arg(
+			long, value_name = "BYTES", default_value_t = 64 * 1024,
+			help = "Data downloaded but not yet uploaded is buffered up to this size."
+		)]
+		buffer: usize,
+
+		#[arg(
+			short, long, default_value_t = tftp::consts::TFTP_LISTEN_PORT,
+			help = "The remote port for servers without one."
+		)]
+		port: u16,
+	},
+	/// Find out which options and modes a server supports, by requesting REMOTE in
+	/// various ways. Transfers are aborted after the first reply.
+	Probe {
+		#[arg(value_name = "SERVER|URI", help = "The server to probe, or a tftp:// URI naming the file.")]
+		server: ServerTarget,
+
+		#[arg(help = "A file the server lets us read, unless SERVER is a URI.")]
+		remote: Option<String>,
+
+		#[arg(short, long, default_value_t = tftp::consts::TFTP_LISTEN_PORT)]
+		port: u16,
+	},
+	/// Interactive prompt like tftp(1), type 'help' for its commands.
+	Shell {
+		#[arg(value_name = "SERVER", help = "The server to connect to right away.")]
+		server: Option<ServerTarget>,
+
+		#[arg(short, long, default_value_t = tftp::consts::TFTP_LISTEN_PORT)]
+		port: u16,
+	},
+	/// Run the transfers listed in a manifest, one per line:
+	/// `get|put SERVER FILES... [OPTION=VALUE...]`, e.g.
+	/// `get 192.0.2.1 pxelinux.cfg/default boot/default blksize=1428`.
+	/// FILES are given as for get and put; OPTIONs are blksize, timeout, tsize=yes|no, windowsize,
+	/// mode or any other option to request. '#' starts a comment.
+	#[command(verbatim_doc_comment)]
+	Batch {
+		#[arg(help = "The manifest, or '-' to read it from stdin.")]
+		manifest: PathBuf,
+
+		#[arg(short, long, default_value_t = 4, help = "Transfers to run at the same time.")]
+		jobs: usize,
+
+		#[arg(
+			short, long, default_value_t = tftp::consts::TFTP_LISTEN_PORT,
+			help = "The remote port for servers without one."
+		)]
+		port: u16,
+	},
 }
+#[cfg(feature = "client")]
 impl ClientAction {
-	pub fn as_request_kind(self) -> tftp::RequestKind {
+	pub fn as_request_kind(&self) -> tftp::RequestKind {
 		match self {
-			Self::Get { opts: _ } => tftp::RequestKind::Rrq,
-			Self::Put { opts: _ } => tftp::RequestKind::Wrq,
+			Self::Get { .. } => tftp::RequestKind::Rrq,
+			Self::Put { .. } => tftp::RequestKind::Wrq,
+			Self::Relay { .. } | Self::Probe { .. } | Self::Shell { .. } | Self::Batch { .. } => {
+				unreachable!("relay, probe, shell and batch run their own transfers")
+			},
 		}
 	}
 
 	pub fn options(&self) -> &ClientActionOpts {
 		match self {
-			Self::Get { opts } => opts,
-			Self::Put { opts } => opts,
+			Self::Get { opts, .. } => opts,
+			Self::Put { opts, .. } => opts,
+			Self::Relay { .. } | Self::Probe { .. } | Self::Shell { .. } | Self::Batch { .. } => {
+				unreachable!("relay, probe, shell and batch run their own transfers")
+			},
+		}
+	}
+
+	/// The local file and the name of the file on the server, like tftp(1) picks them.
+	pub fn files(&self) -> Result<(PathBuf, String), String> {
+		match self {
+			Self::Get { opts, remote, local, .. } => {
+				/* With a URI the first file given is the local one */
+				let (remote, local) = match (opts.server.file.as_ref(), remote, local) {
+					(Some(_), _, Some(_)) => return Err("too many files given along with a URI".to_string()),
+					(Some(uri_file), local, None) => (uri_file, local.as_ref().map(PathBuf::from)),
+					(None, Some(remote), local) => (remote, local.clone()),
+					(None, None, _) => return Err("REMOTE is required unless SERVER is a URI".to_string()),
+				};
+				let local = match local {
+					Some(local) => local.clone(),
+					None => default_local(remote)?,
+				};
+				Ok((local, remote.clone()))
+			},
+			Self::Put { opts, local, remote } => {
+				let remote = match (opts.server.file.as_ref(), remote) {
+					(Some(_), Some(_)) => return Err("too many files given along with a URI".to_string()),
+					(Some(remote), None) | (None, Some(remote)) => remote.clone(),
+					(None, None) if is_stdio(local) => return Err("REMOTE is required when uploading from stdin".to_string()),
+					(None, None) => default_remote(local)?,
+				};
+				Ok((local.clone(), remote))
+			},
+			Self::Relay { .. } | Self::Probe { .. } | Self::Shell { .. } | Self::Batch { .. } => {
+				unreachable!("relay, probe, shell and batch run their own transfers")
+			},
 		}
 	}
 }
 
+/// Local name of a downloaded file: the last component of its remote name.
+#[cfg(feature = "client")]
+pub fn default_local(remote: &str) -> Result<PathBuf, String> {
+	match remote.rsplit('/').next() {
+		Some(name) if !name.is_empty() => Ok(PathBuf::from(name)),
+		_ => Err(format!("can't derive a local file name from '{}'", remote)),
+	}
+}
+
+/// Remote name of an uploaded file: its file name without directories.
+#[cfg(feature = "client")]
+pub fn default_remote(local: &Path) -> Result<String, String> {
+	match local.file_name() {
+		Some(name) => Ok(name.to_string_lossy().into_owned()),
+		None => Err(format!("can't derive a remote file name from '{}'", local.display())),
+	}
+}
+
+/// Whether `path` stands for stdin or stdout.
+#[cfg(feature = "client")]
+pub fn is_stdio(path: &Path) -> bool {
+	path == Path::new("-")
+}
+
+#[cfg(feature = "client")]
 pub fn parse_tftp_options(cli_opts: ClientOpts) -> Vec<TftpOption> {
 	let mut v: Vec<TftpOption> = vec![];
 
@@ -134,20 +436,111 @@ pub fn parse_tftp_options(cli_opts: ClientOpts) -> Vec<TftpOption> {
 	if cli_opts.transfer_size {
 		v.push(TftpOption::TransferSize(0));
 	}
-	if cli_opts.blocksize != tftp::consts::DEFAULT_BLOCK_SIZE {
-		v.push(TftpOption::Blocksize(cli_opts.blocksize));
+	if let Blocksize::Fixed(bs) = cli_opts.blocksize {
+		if bs != tftp::consts::DEFAULT_BLOCK_SIZE {
+			v.push(TftpOption::Blocksize(bs));
+		}
+	}
+	if cli_opts.timeout != Duration::from_secs(tftp::consts::DEFAULT_TIMEOUT_SECS as u64) {
+		v.extend(timeout_options(cli_opts.timeout));
 	}
-	if cli_opts.timeout != tftp::consts::DEFAULT_TIMEOUT_SECS {
-		v.push(TftpOption::Timeout(Duration::from_secs(cli_opts.timeout as u64)))
+	if let Some(ws) = cli_opts.windowsize {
+		v.push(TftpOption::WindowSize(ws));
 	}
+	if cli_opts.multicast {
+		v.push(TftpOption::Multicast(None));
+	}
+	v.extend(cli_opts.custom_options);
 
 	v
 }
 
+/// Options requesting `timeout`: timeout, plus utimeout for fractions of seconds.
+#[cfg(feature = "client")]
+pub fn timeout_options(timeout: Duration) -> Vec<TftpOption> {
+	/* Servers without utimeout support still get the timeout rounded up to seconds */
+	let secs = timeout.as_secs() + (timeout.subsec_nanos() > 0) as u64;
+	let mut v = vec![TftpOption::Timeout(Duration::from_secs(secs.clamp(1, 255)))];
+	if timeout.subsec_nanos() > 0 {
+		v.push(TftpOption::UTimeout(timeout));
+	}
+	v
+}
+
+pub fn parse_timeout(s: &str) -> Result<Duration, String> {
+	let secs = s.parse::<f64>().map_err(|e| e.to_string())?;
+	let min = tftp::consts::MIN_UTIMEOUT_USECS as f64 / 1e6;
+	let max = tftp::consts::MAX_UTIMEOUT_USECS as f64 / 1e6;
+	if !(min..=max).contains(&secs) {
+		return Err(format!("must be between {} and {} seconds", min, max));
+	}
+	Ok(Duration::from_micros((secs * 1e6).round() as u64))
+}
+
+/// The retry policy given on the command line, with `default_retries` if `--retries` is missing.
+#[cfg(feature = "client")]
+pub fn retry_policy(cli_opts: &ClientOpts, default_retries: u32) -> RetryPolicy {
+	let mut policy = RetryPolicy::new(cli_opts.retries.unwrap_or(default_retries).saturating_add(1));
+	policy.initial_backoff = cli_opts.retry_backoff;
+	policy.max_backoff = policy.max_backoff.max(cli_opts.retry_backoff);
+	if !cli_opts.retry_on.is_empty() {
+		policy.retry_on = cli_opts.retry_on.clone();
+	}
+	policy
+}
+
+fn parse_backoff(s: &str) -> Result<Duration, String> {
+	match s.parse::<f64>() {
+		Ok(secs) if (0.0..=3600.0).contains(&secs) => Ok(Duration::from_secs_f64(secs)),
+		_ => Err("expected a number of seconds up to 3600".to_string()),
+	}
+}
+
+#[cfg(feature = "client")]
+pub fn parse_retry_condition(s: &str) -> Result<RetryCondition, String> {
+	let cond = match s.split_once('=') {
+		None => match s {
+			"timeout" => RetryCondition::Timeout,
+			"no-response" => RetryCondition::NoResponse,
+			"unknown-tid" => RetryCondition::UnknownTid,
+			"io" => RetryCondition::Io,
+			"busy" => RetryCondition::PeerMessage("busy".to_string()),
+			_ => return Err(format!("unknown condition '{}'", s)),
+		},
+		Some(("error", code)) => {
+			let code = code
+				.parse::<u16>()
+				.ok()
+				.and_then(|code| ErrorCode::try_from(code).ok())
+				.ok_or_else(|| format!("'{}' isn't a TFTP error code", code))?;
+			RetryCondition::PeerError(code)
+		},
+		Some(("message", text)) if !text.is_empty() => RetryCondition::PeerMessage(text.to_string()),
+		_ => return Err(format!("unknown condition '{}'", s)),
+	};
+	Ok(cond)
+}
+
+pub fn parse_custom_option(s: &str) -> Result<TftpOption, String> {
+	match s.split_once('=') {
+		Some((name, value)) if !name.is_empty() && !tftp::options::is_builtin_option(name) => {
+			Ok(TftpOption::Custom(name.to_string(), value.to_string()))
+		},
+		Some((name, _)) if !name.is_empty() => Err(format!("'{}' has its own command line switch", name)),
+		_ => Err("expected NAME=VALUE".to_string()),
+	}
+}
+
+/// The logger lets everything pass, the level is set via `log::set_max_level` so it
+/// can be changed later on, e.g. by the shell's `trace` command.
 pub fn init_logger(debug_level: DebugLevel) {
-	SimpleLogger::new()
+	let logger = SimpleLogger::new()
 		.with_level(debug_level.into())
-		.env()
+		.env();
+	let max_level = logger.max_level();
+	logger
+		.with_level(log::LevelFilter::Trace)
 		.init()
 		.unwrap();
+	log::set_max_level(max_level);
 }
\ No newline at end of file
@@ -1,172 +1,863 @@
 use std::fs::OpenOptions;
-use std::path::PathBuf;
-use std::net::{SocketAddr, IpAddr, Ipv4Addr};
-use std::io;
+use std::path::{Path, PathBuf};
+use std::net::{SocketAddr, IpAddr, Ipv4Addr, Ipv6Addr};
+use std::io::{self, Seek};
+use std::sync::Arc;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::task::{Context, Poll};
+use std::time::Duration;
 
+use bytes::Bytes;
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream, ReadBuf};
 use tokio_util::sync::CancellationToken;
 
 #[allow(unused)]
 use log::{info, warn, error, debug, trace};
 
-use crate::cli;
-use crate::tftp::options::{TftpOption, TftpOptionKind};
-use crate::tftp::{self, Mode, RequestKind, TftpConnection};
+use crate::tftp::options::{OptionRegistry, TftpOption, TftpOptionKind};
+use crate::tftp::checksum::{Algorithm, Checksum, Hasher, HashingStream};
+use crate::tftp::stats::{ProgressObserver, TransferStats};
+use crate::tftp::{self, utils, Mode, RequestKind, TftpConnection};
 use crate::tftp::packet::{builder::*, TftpPacket};
-use crate::tftp::error::{ConnectionError, RequestError};
+use crate::tftp::error::{ConnectionError, ErrorCode, OptionError, RequestError, TftpError};
 
 pub type Result<T> = std::result::Result<T, RequestError>;
 
+///
+/// Describes a single transfer, see `TftpClient::request`. Settings left at `None`
+/// fall back to those of the `TftpClient`.
+///
+#[derive(Clone)]
 pub struct TftpRequestParameters<'a> {
 	pub req_kind: RequestKind,
 	pub server: SocketAddr,
+	/// Local file written by an RRQ or read by a WRQ.
 	pub file: PathBuf,
+	/// Name of the file on the server, sent as is. Defaults to the name of `file`.
+	pub remote: Option<String>,
+	/// Options for this request, in addition to and replacing those of the client.
 	pub options: &'a [TftpOption],
+	pub mode: Mode,
+	/// Timeout for replies in case none is negotiated.
+	pub timeout: Option<Duration>,
+	pub dally: Option<Duration>,
+	/// Told about every block transferred.
+	pub progress: Option<Arc<dyn ProgressObserver>>,
+	pub retry: Option<RetryPolicy>,
+	/// What the data transferred must hash to. Downloads to a file don't replace it
+	/// unless it does.
+	pub checksum: Option<Checksum>,
+	/// Hash the data transferred, see `TransferStats::digest`. Implied by `checksum`.
+	pub digest: Option<Algorithm>,
+}
+impl std::fmt::Debug for TftpRequestParameters<'_> {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("TftpRequestParameters")
+			.field("req_kind", &self.req_kind)
+			.field("server", &self.server)
+			.field("file", &self.file)
+			.field("remote", &self.remote)
+			.field("options", &self.options)
+			.field("mode", &self.mode)
+			.field("timeout", &self.timeout)
+			.field("dally", &self.dally)
+			.field("progress", &self.progress.is_some())
+			.field("retry", &self.retry)
+			.field("checksum", &self.checksum)
+			.field("digest", &self.digest)
+			.finish()
+	}
+}
+impl<'a> TftpRequestParameters<'a> {
+	pub fn new(req_kind: RequestKind, server: SocketAddr, file: PathBuf) -> Self {
+		Self {
+			req_kind,
+			server,
+			file,
+			remote: None,
+			options: &[],
+			mode: Mode::Octet,
+			timeout: None,
+			dally: None,
+			progress: None,
+			retry: None,
+			checksum: None,
+			digest: None,
+		}
+	}
+
+	pub fn remote(mut self, remote: &str) -> Self {
+		self.remote = Some(remote.to_string());
+		self
+	}
+	pub fn options(mut self, options: &'a [TftpOption]) -> Self {
+		self.options = options;
+		self
+	}
+	pub fn mode(mut self, mode: Mode) -> Self {
+		self.mode = mode;
+		self
+	}
+	pub fn timeout(mut self, timeout: Duration) -> Self {
+		self.timeout = Some(timeout);
+		self
+	}
+	pub fn dally(mut self, dally: Duration) -> Self {
+		self.dally = Some(dally);
+		self
+	}
+	pub fn progress(mut self, observer: Arc<dyn ProgressObserver>) -> Self {
+		self.progress = Some(observer);
+		self
+	}
+	pub fn retry(mut self, policy: RetryPolicy) -> Self {
+		self.retry = Some(policy);
+		self
+	}
+	pub fn checksum(mut self, checksum: Checksum) -> Self {
+		self.checksum = Some(checksum);
+		self
+	}
+	pub fn digest(mut self, algorithm: Algorithm) -> Self {
+		self.digest = Some(algorithm);
+		self
+	}
+
+	fn digest_algorithm(&self) -> Option<Algorithm> {
+		self.checksum.as_ref().map(Checksum::algorithm).or(self.digest)
+	}
+
+	/// Fails if the data transferred doesn't match `checksum`.
+	fn verify(&self, digest: Option<&Checksum>) -> Result<()> {
+		match (self.checksum.as_ref(), digest) {
+			(Some(expected), Some(actual)) if expected != actual => {
+				Err(RequestError::ChecksumMismatch(expected.clone(), actual.clone()))
+			},
+			_ => Ok(()),
+		}
+	}
+
+	/// The filename sent to the server.
+	fn remote_name(&self) -> Result<String> {
+		match self.remote.as_ref() {
+			Some(remote) => Ok(remote.clone()),
+			None => Ok(self.file.file_name().ok_or(RequestError::FileNotFound)?.to_string_lossy().into_owned()),
+		}
+	}
+}
+
+///
+/// Failures after which a whole transfer is worth another try.
+///
+#[derive(Debug, Clone, PartialEq)]
+pub enum RetryCondition {
+	/// The server didn't reply to the request at all.
+	NoResponse,
+	/// The peer stopped replying in the middle of the transfer.
+	Timeout,
+	/// Packets from an unexpected port or host showed up.
+	UnknownTid,
+	/// An ERROR with this code.
+	PeerError(ErrorCode),
+	/// An ERROR whose message contains this text, ignoring case, e.g. "busy".
+	PeerMessage(String),
+	/// Local or network I/O failed, e.g. the network was unreachable.
+	Io,
+}
+impl RetryCondition {
+	pub fn matches(&self, error: &RequestError) -> bool {
+		match (self, error) {
+			(Self::NoResponse, RequestError::NoResponse) => true,
+			(Self::Timeout, RequestError::ConnectionError(ConnectionError::Timeout)) => true,
+			(Self::UnknownTid, RequestError::ConnectionError(ConnectionError::UnknownTid)) => true,
+			(Self::UnknownTid, RequestError::UnknownPeer) => true,
+			(Self::PeerError(code), RequestError::ConnectionError(ConnectionError::PeerError(e))) => e.code() == *code,
+			(Self::PeerMessage(text), RequestError::ConnectionError(ConnectionError::PeerError(e))) => {
+				e.msg().to_ascii_lowercase().contains(&text.to_ascii_lowercase())
+			},
+			(Self::Io, RequestError::ConnectionError(ConnectionError::IO(_))) => true,
+			(Self::Io, RequestError::OtherHostError(_) | RequestError::Unreachable(_)) => true,
+			_ => false,
+		}
+	}
+}
+
+///
+/// When to start a failed transfer over, and how long to wait in between. The wait
+/// starts at `initial_backoff` and doubles with every attempt, up to `max_backoff`.
+///
+/// Only transfers from and to files are retried, data handed to a sink or taken from
+/// a source can't be taken back. The default doesn't retry at all.
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+	/// Including the first one, so 1 means no retries.
+	pub max_attempts: u32,
+	pub initial_backoff: Duration,
+	pub max_backoff: Duration,
+	pub retry_on: Vec<RetryCondition>,
+}
+impl RetryPolicy {
+	/// Retries on timeouts, unknown TIDs and servers saying they are busy.
+	pub fn new(max_attempts: u32) -> Self {
+		Self {
+			max_attempts,
+			initial_backoff: Duration::from_secs(1),
+			max_backoff: Duration::from_secs(30),
+			retry_on: vec![
+				RetryCondition::NoResponse,
+				RetryCondition::Timeout,
+				RetryCondition::UnknownTid,
+				RetryCondition::PeerMessage("busy".to_string()),
+			],
+		}
+	}
+
+	pub fn is_retryable(&self, error: &RequestError) -> bool {
+		self.retry_on.iter().any(|cond| cond.matches(error))
+	}
+
+	/// How long to wait after the given (failed) attempt, counting from 1.
+	pub fn backoff(&self, attempt: u32) -> Duration {
+		let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+		self.initial_backoff.saturating_mul(factor).min(self.max_backoff)
+	}
+}
+impl Default for RetryPolicy {
+	fn default() -> Self {
+		Self::new(1)
+	}
+}
+
+/// The local end of a transfer.
+enum Local<'s> {
+	File(&'s Path),
+	Sink(&'s mut (dyn AsyncWrite + Unpin + Send)),
+	/// With the size of the data, if known.
+	Source(&'s mut (dyn AsyncRead + Unpin + Send), Option<u64>),
 }
 
 pub struct TftpClient {
 	local_addr: IpAddr,
 	cxl_token: CancellationToken,
 	options: Vec<TftpOption>,
+	dally: Option<Duration>,
+	auto_blocksize: bool,
+	probe_blocksize: bool,
+	registry: OptionRegistry,
+	retry: RetryPolicy,
 }
 impl TftpClient {
 	pub fn new(cxl_token: CancellationToken) -> Self {
 		Self {
 			local_addr: IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)),
 			cxl_token,
-			options: Vec::new()
+			options: Vec::new(),
+			dally: None,
+			auto_blocksize: false,
+			probe_blocksize: false,
+			registry: OptionRegistry::default(),
+			retry: RetryPolicy::default(),
 		}
 	}
 
 	pub fn set_local_addr(&mut self, addr: IpAddr) {
 		self.local_addr = addr
 	}
+	/// Token for cancelling transfers started afterwards, e.g. to replace one that
+	/// was cancelled already.
+	pub fn set_cancellation_token(&mut self, cxl_token: CancellationToken) {
+		self.cxl_token = cxl_token
+	}
+	/// How long to linger after the final ACK of a download, re-ACKing the last block
+	/// if the server retransmits it. `None` (the default) ends the download right away.
+	pub fn set_dally_period(&mut self, dally: Option<Duration>) {
+		self.dally = dally
+	}
+	/// Derive the blksize from the MTU of the path towards the server, instead of
+	/// requesting a fixed one.
+	pub fn set_auto_blocksize(&mut self, auto: bool) {
+		self.auto_blocksize = auto
+	}
+	/// Restart a transfer with a smaller blksize when full-size blocks keep timing out,
+	/// which usually means fragmented packets are dropped somewhere on the path.
+	pub fn set_blocksize_probing(&mut self, probe: bool) {
+		self.probe_blocksize = probe
+	}
+	/// Handlers for custom options, used to validate and apply their values when the
+	/// server acknowledges them.
+	pub fn set_option_registry(&mut self, registry: OptionRegistry) {
+		self.registry = registry
+	}
+	/// Used for requests that don't bring their own.
+	pub fn set_retry_policy(&mut self, policy: RetryPolicy) {
+		self.retry = policy
+	}
 	pub fn add_option(&mut self, option: &TftpOption) {
-		for x in 0..self.options.len() {
-			if self.options[x].kind() == option.kind() {
-				self.options[x] = option.clone();
-				return;
+		set_option(&mut self.options, option.clone())
+	}
+
+	/// The address to bind to for talking to `server`; an unspecified local address
+	/// follows the address family of the server.
+	fn local_addr_for(&self, server: &SocketAddr) -> IpAddr {
+		match (self.local_addr, server) {
+			(IpAddr::V4(addr), SocketAddr::V6(_)) if addr.is_unspecified() => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+			(IpAddr::V6(addr), SocketAddr::V4(_)) if addr.is_unspecified() => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+			(addr, _) => addr,
+		}
+	}
+
+	/// Option sets to try one after another in case the server refuses our options:
+	/// everything we were asked for, then without blksize, then no options at all.
+	fn option_fallbacks(options: &[TftpOption]) -> Vec<Vec<TftpOption>> {
+		let mut fallbacks = vec![options.to_vec()];
+		if options.len() > 1 && options.iter().any(|e| e.kind() == TftpOptionKind::Blocksize) {
+			fallbacks.push(
+				options.iter().filter(|e| e.kind() != TftpOptionKind::Blocksize).cloned().collect()
+			);
+		}
+		if !options.is_empty() {
+			fallbacks.push(Vec::new());
+		}
+		fallbacks
+	}
+
+	pub async fn get(&self, path: PathBuf, server: SocketAddr) -> Result<TransferStats> {
+		self.request(&TftpRequestParameters::new(RequestKind::Rrq, server, path)).await
+	}
+
+	pub async fn put(&self, path: PathBuf, server: SocketAddr) -> Result<TransferStats> {
+		self.request(&TftpRequestParameters::new(RequestKind::Wrq, server, path)).await
+	}
+
+	///
+	/// Runs a single transfer as described by `params` and returns its statistics,
+	/// including the negotiated options. Requests don't affect each other, so one client may run any number
+	/// of them concurrently.
+	///
+	/// Downloads only replace `file` once complete and, if the server announced the
+	/// size via tsize, of that size. On failure `file` is left as it was.
+	///
+	pub async fn request(&self, params: &TftpRequestParameters<'_>) -> Result<TransferStats> {
+		self.transfer(params, Local::File(&params.file)).await
+	}
+
+	///
+	/// Like `request`, but also returns how often the transfer was attempted under the
+	/// retry policy, which on success is `attempts` of the statistics as well.
+	///
+	pub async fn request_counted(&self, params: &TftpRequestParameters<'_>) -> (Result<TransferStats>, u32) {
+		let mut attempts = 0;
+		let res = self.transfer_counted(params, Local::File(&params.file), &mut attempts).await;
+		(res, attempts)
+	}
+
+	///
+	/// Downloads into `sink` instead of a file. `req_kind` and `file` of `params` are
+	/// ignored, so `remote` should be set.
+	///
+	/// Neither multicast nor blksize probing are available here; both need to write
+	/// blocks again that were already handed to the sink.
+	///
+	pub async fn get_to(&self, params: &TftpRequestParameters<'_>, mut sink: impl AsyncWrite + Unpin + Send) -> Result<TransferStats> {
+		let params = TftpRequestParameters { req_kind: RequestKind::Rrq, ..params.clone() };
+		self.transfer(&params, Local::Sink(&mut sink)).await
+	}
+
+	///
+	/// Uploads from `source` instead of a file. `req_kind` and `file` of `params` are
+	/// ignored, so `remote` should be set. Without a `size`, tsize isn't requested.
+	///
+	pub async fn put_from(
+		&self,
+		params: &TftpRequestParameters<'_>,
+		mut source: impl AsyncRead + Unpin + Send,
+		size: Option<u64>
+	) -> Result<TransferStats> {
+		let params = TftpRequestParameters { req_kind: RequestKind::Wrq, ..params.clone() };
+		self.transfer(&params, Local::Source(&mut source, size)).await
+	}
+
+	/// Downloads into memory.
+	pub async fn get_vec(&self, params: &TftpRequestParameters<'_>) -> Result<(Vec<u8>, TransferStats)> {
+		let mut data: Vec<u8> = Vec::new();
+		let stats = self.get_to(params, &mut data).await?;
+		Ok((data, stats))
+	}
+
+	/// Downloads into memory.
+	pub async fn get_bytes(&self, params: &TftpRequestParameters<'_>) -> Result<(Bytes, TransferStats)> {
+		self.get_vec(params).await.map(|(data, stats)| (Bytes::from(data), stats))
+	}
+
+	/// Uploads `data`, e.g. a `Vec<u8>` or `Bytes`.
+	pub async fn put_bytes(&self, params: &TftpRequestParameters<'_>, data: &[u8]) -> Result<TransferStats> {
+		self.put_from(params, data, Some(data.len() as u64)).await
+	}
+
+	///
+	/// Copies a file from one server to another without storing it locally: the download
+	/// described by `from` is piped into the upload described by `to` through a buffer of
+	/// `buffer` bytes, so the faster side waits for the slower one. Returns the statistics
+	/// of both.
+	///
+	/// `from` and `to` are used like for `get_to` and `put_from`. The size isn't known when
+	/// the upload starts, so no tsize is sent to `to`. If either side fails, the other
+	/// one is aborted with an ERROR, so neither server is left waiting and `to` doesn't
+	/// keep a truncated file. The error returned is that of the side failing first.
+	///
+	pub async fn relay(
+		&self,
+		from: &TftpRequestParameters<'_>,
+		to: &TftpRequestParameters<'_>,
+		buffer: usize
+	) -> Result<(TransferStats, TransferStats)> {
+		let (reader, mut writer) = tokio::io::duplex(buffer.max(1));
+		let download_failed = AtomicBool::new(false);
+		let upload_failed = AtomicBool::new(false);
+
+		/* A failed upload drops the reader, which fails the download's next write. A
+		 * failed download has to fail the upload's next read instead of just ending the
+		 * file, so it is marked as such before the writer goes. */
+		let download = async {
+			let res = self.get_to(from, &mut writer).await;
+			if res.is_err() && !upload_failed.load(Ordering::Relaxed) {
+				download_failed.store(true, Ordering::Relaxed);
+			}
+			drop(writer);
+			res
+		};
+		let upload = async {
+			let reader = RelayReader { inner: reader, download_failed: &download_failed };
+			let res = self.put_from(to, reader, None).await;
+			if res.is_err() && !download_failed.load(Ordering::Relaxed) {
+				upload_failed.store(true, Ordering::Relaxed);
+			}
+			res
+		};
+		match tokio::join!(download, upload) {
+			(Ok(from), Ok(to)) => Ok((from, to)),
+			(Err(e), _) if download_failed.load(Ordering::Relaxed) => Err(e),
+			(_, Err(e)) | (Err(e), _) => Err(e),
+		}
+	}
+
+	async fn transfer(&self, params: &TftpRequestParameters<'_>, local: Local<'_>) -> Result<TransferStats> {
+		self.transfer_counted(params, local, &mut 0).await
+	}
+
+	async fn transfer_counted(
+		&self,
+		params: &TftpRequestParameters<'_>,
+		mut local: Local<'_>,
+		attempts: &mut u32
+	) -> Result<TransferStats> {
+		let (kind, server) = (params.req_kind, params.server);
+		let mut options = self.options.clone();
+		params.options
+			.iter()
+			.for_each(|opt| set_option(&mut options, opt.clone()));
+		if kind == RequestKind::Wrq || !matches!(local, Local::File(_)) {
+			/* Multicast is for downloads only (RFC 2090), and blocks arrive out of order */
+			options.retain(|e| e.kind() != TftpOptionKind::Multicast);
+		}
+		if self.auto_blocksize {
+			match utils::path_mtu_to(self.local_addr_for(&server), server) {
+				Some(mtu) => {
+					let blocksize = utils::blocksize_for_mtu(mtu, &server);
+					debug!("path MTU towards {} is {}, requesting blksize {}", server, mtu, blocksize);
+					set_option(&mut options, TftpOption::Blocksize(blocksize));
+				},
+				None => warn!("path MTU towards {} unknown, using the default blksize", server),
 			}
 		}
 
-		self.options.push(option.clone())
+		let policy = params.retry.as_ref().unwrap_or(&self.retry);
+		let mut attempt: u32 = 1;
+		loop {
+			*attempts = attempt;
+			let restartable = matches!(local, Local::File(_));
+			let res = self.transfer_with_fallbacks(params, &options, &mut local).await;
+			/* Once there is no smaller blksize left, timeouts are up to the retry policy */
+			let smaller = match res {
+				Err(RequestError::ConnectionError(ConnectionError::Timeout)) if self.probe_blocksize && restartable => options
+					.iter()
+					.find_map(|e| match e { TftpOption::Blocksize(bs) => Some(*bs), _ => None })
+					.and_then(|bs| next_probe_blocksize(bs, &server)),
+				_ => None,
+			};
+			match (res, smaller) {
+				(Err(_), Some(smaller)) => {
+					warn!("transfer timed out, retrying with blksize {}", smaller);
+					set_option(&mut options, TftpOption::Blocksize(smaller));
+				},
+				(Err(e), _) if restartable && attempt < policy.max_attempts && policy.is_retryable(&e) && !self.cxl_token.is_cancelled() => {
+					let delay = policy.backoff(attempt);
+					warn!("transfer failed: {}, retrying in {:.1}s (attempt {} of {})", e, delay.as_secs_f64(), attempt + 1, policy.max_attempts);
+					tokio::select! {
+						_ = tokio::time::sleep(delay) => (),
+						_ = self.cxl_token.cancelled() => return Err(ConnectionError::Cancelled.into()),
+					}
+					attempt += 1;
+				},
+				(Ok(mut stats), _) => {
+					stats.attempts = attempt;
+					return Ok(stats);
+				},
+				(res, _) => return res,
+			}
+		}
 	}
 
-	pub async fn get(&mut self, path: PathBuf, server: SocketAddr) -> Result<()> {
-		let mut conn = TftpConnection::new(self.local_addr, self.cxl_token.clone())?;
+	async fn transfer_with_fallbacks(
+		&self,
+		params: &TftpRequestParameters<'_>,
+		options: &[TftpOption],
+		local: &mut Local<'_>
+	) -> Result<TransferStats> {
+		let mut fallbacks = Self::option_fallbacks(options).into_iter().peekable();
+		while let Some(options) = fallbacks.next() {
+			let res = match params.req_kind {
+				RequestKind::Rrq => self.try_get(params, &options, local).await,
+				RequestKind::Wrq => self.try_put(params, &options, local).await,
+			};
+			match res {
+				Err(RequestError::OptionNegotiationFailed(OptionError::Refused)) if fallbacks.peek().is_some() => {
+					warn!("server refused options, retrying with fewer options")
+				},
+				res => return res,
+			}
+		}
+		unreachable!("there is always at least one set of options to try")
+	}
 
-		let filename = path.file_name().ok_or(RequestError::FileNotFound)?.to_string_lossy();
-		let file = match OpenOptions::new().create(true).write(true).truncate(true).open(&path) {
-			Ok(f) => f,
-			Err(e) if e.kind() == io::ErrorKind::PermissionDenied => return Err(RequestError::FileNotAccessible),
-			Err(e) => return Err(RequestError::OtherHostError(e))
+	/// A connection set up as `params` asks for.
+	async fn connection(&self, params: &TftpRequestParameters<'_>) -> Result<TftpConnection> {
+		let mut conn = TftpConnection::new(self.local_addr_for(&params.server), self.cxl_token.clone())?;
+		conn.set_tx_mode(params.mode).await?;
+		conn.set_dally_period(params.dally.or(self.dally));
+		if let Some(timeout) = params.timeout {
+			conn.set_default_timeout(timeout);
+		}
+		conn.set_progress_observer(params.progress.clone());
+		Ok(conn)
+	}
+
+	async fn try_get(
+		&self,
+		params: &TftpRequestParameters<'_>,
+		options: &[TftpOption],
+		local: &mut Local<'_>
+	) -> Result<TransferStats> {
+		let server = params.server;
+		let mut conn = self.connection(params).await?;
+
+		let filename = params.remote_name()?;
+		/* The destination is only replaced once the download is complete */
+		let part = match local {
+			Local::File(path) => match PartialFile::create(path) {
+				Ok(part) => Some(part),
+				Err(e) if e.kind() == io::ErrorKind::PermissionDenied => return Err(RequestError::FileNotAccessible),
+				Err(e) => return Err(RequestError::OtherHostError(e))
+			},
+			_ => None,
 		};
 		
 		let mut builder = TftpReqBuilder::new()
 			.kind(RequestKind::Rrq)
-			.mode(Mode::Octet)
+			.mode(params.mode)
 			.filename(&filename);
 
-		if self.options.len() > 0 {
-			builder = builder.options(&self.options[..]);
+		if !options.is_empty() {
+			builder = builder.options(options);
 		}
 		let pkt = builder.build();
-		conn.send_request_to(&pkt, server)?;
 
 		/* Handle the first packet coming from the server here instead of in receive_file.
 		 * We don't know which port the server will use to reply, and handling this should
 		 * not be done in TftpConnection's receive functions.
 		 * In case we requested options, we need to handle the first packet anyway. */
 		let mut buf = [0u8; 4 + tftp::consts::DEFAULT_BLOCK_SIZE as usize];
-		let (pkt, remote) = conn.receive_packet_from(&mut buf)?;
+		let (pkt, remote) = conn
+			.send_request_and_receive(&pkt, server, &mut buf)
+			.await
+			.map_err(|e| match e {
+				ConnectionError::Timeout => RequestError::NoResponse,
+				ConnectionError::IO(e) => RequestError::Unreachable(e),
+				e => e.into(),
+			})?;
 
 		// Fail if another IP is used
 		if remote.ip() != server.ip() {
 			return Err(RequestError::UnknownPeer);
 		}
-		conn.connect_to(remote)?;
+		conn.connect_to(remote).await?;
 
 		let mut init_data: Option<_> = None;
+		let mut expected_size: Option<u64> = None;
 		match pkt {
 			TftpPacket::OAck(oack) => {
-				let opts = tftp::options::parse_tftp_options(
-					oack.options().map_err(|e| ConnectionError::from(e))?
-				)?;
-				conn.set_options(&opts[..]);
+				let raw_opts = oack.options().map_err(ConnectionError::from)?;
+				let opts = match tftp::options::validate_oack(options, raw_opts, RequestKind::Rrq, &self.registry) {
+					Ok(opts) => opts,
+					Err(e) => {
+						conn.send_error(ErrorCode::InvalidOption, &e.to_string()).await.ok();
+						return Err(e.into());
+					},
+				};
+				conn.set_options(&opts[..], &self.registry);
+				if opts.iter().any(|e| e.kind() == TftpOptionKind::TransferSize) {
+					expected_size = Some(conn.options().transfer_size as u64);
+					conn.set_expected_size(expected_size);
+				}
 
-				let ack_pkt = tftp::packet::MutableTftpAck::new(0);
-				conn.send_packet(&ack_pkt)?;
+				/* In a multicast session only the master client ACKs */
+				if conn.options().multicast.is_none() {
+					let ack_pkt = tftp::packet::MutableTftpAck::new(0);
+					conn.send_packet(&ack_pkt).await?;
+				}
+			},
+			TftpPacket::Data(data) => {
+				if !options.is_empty() {
+					info!("server ignored our options, using defaults");
+				}
+				init_data = Some(data)
 			},
-			TftpPacket::Data(data) => init_data = Some(data),
+			TftpPacket::Err(e) => return Err(request_refusal(e, options)),
 			_ => return Err(ConnectionError::UnexpectedPacket.into()),
 		}
-		conn.receive_data(file, init_data).await?;
-		Ok(())
+		let stats = match (part, local) {
+			(Some(mut part), _) => {
+				let mut stats = match conn.options().multicast {
+					Some(mcast) => conn.receive_multicast(&mut part.file, mcast).await?,
+					None => {
+						let mut file = tokio::fs::File::from_std(part.file.try_clone()?);
+						conn.receive_data(&mut file, init_data, None).await?
+					},
+				};
+				if let Some(size) = expected_size.filter(|size| *size != stats.bytes) {
+					return Err(RequestError::SizeMismatch(size, stats.bytes));
+				}
+				/* Multicast blocks arrive out of order, so the file is hashed once complete */
+				if let Some(algorithm) = params.digest_algorithm() {
+					part.file.seek(io::SeekFrom::Start(0))?;
+					stats.digest = Some(Hasher::digest_reader(algorithm, &mut part.file)?);
+				}
+				params.verify(stats.digest.as_ref())?;
+				part.persist()?;
+				stats
+			},
+			(None, Local::Sink(sink)) => {
+				let mut sink = HashingStream::new(&mut **sink, params.digest_algorithm());
+				let mut stats = conn.receive_data(&mut sink, init_data, None).await?;
+				stats.digest = sink.finish();
+				params.verify(stats.digest.as_ref())?;
+				stats
+			},
+			(None, _) => unreachable!("downloads go to a file or a sink"),
+		};
+		Ok(stats)
 	}
 
-	pub async fn put(&mut self, path: PathBuf, server: SocketAddr) -> Result<()> {
-		let mut conn = TftpConnection::new(self.local_addr, self.cxl_token.clone())?;
+	async fn try_put(
+		&self,
+		params: &TftpRequestParameters<'_>,
+		options: &[TftpOption],
+		local: &mut Local<'_>
+	) -> Result<TransferStats> {
+		let server = params.server;
+		let mut conn = self.connection(params).await?;
 
-		let filename = path.file_name().ok_or(RequestError::FileNotFound)?.to_string_lossy();
-		let file = match OpenOptions::new().read(true).open(&path) {
-			Ok(f) => f,
-			Err(e) if e.kind() == io::ErrorKind::NotFound => return Err(RequestError::FileNotFound),
-			Err(e) if e.kind() == io::ErrorKind::PermissionDenied => return Err(RequestError::FileNotAccessible),
-			Err(e) => return Err(RequestError::OtherHostError(e))
+		let filename = params.remote_name()?;
+		let (file, size) = match local {
+			Local::File(path) => match OpenOptions::new().read(true).open(path) {
+				Ok(f) => {
+					let size = f.metadata()?.len();
+					(Some(tokio::fs::File::from_std(f)), Some(size))
+				},
+				Err(e) if e.kind() == io::ErrorKind::NotFound => return Err(RequestError::FileNotFound),
+				Err(e) if e.kind() == io::ErrorKind::PermissionDenied => return Err(RequestError::FileNotAccessible),
+				Err(e) => return Err(RequestError::OtherHostError(e))
+			},
+			Local::Source(_, size) => (None, *size),
+			Local::Sink(_) => unreachable!("uploads come from a file or a source"),
 		};
 
 		let mut builder = TftpReqBuilder::new()
 			.kind(RequestKind::Wrq)
-			.mode(Mode::Octet) // we only support octet mode
+			.mode(params.mode)
 			.filename(&filename);
 
-		let mut options = self.options.to_owned();
-		if options.len() > 0 {
+		let mut options = options.to_owned();
+		if !options.is_empty() {
 			if let Some(i) = options.iter().position(|e| e.kind() == TftpOptionKind::TransferSize) {
-				options[i] = TftpOption::TransferSize(file.metadata()?.len() as u32);
+				match size {
+					Some(size) => options[i] = TftpOption::TransferSize(size as u32),
+					None => { options.remove(i); },
+				}
+			}
+			if !options.is_empty() {
+				builder = builder.options(&options[..]);
 			}
-			builder = builder.options(&options[..]);
 		}
 		let pkt = builder.build();
-		conn.send_request_to(&pkt, server)?;
 
-		let mut buf = [0u8; 64];
-		let (pkt, remote) = conn.receive_packet_from(&mut buf)?;
+		conn.set_expected_size(size);
+
+		let mut buf = [0u8; 512];
+		let (pkt, remote) = conn
+			.send_request_and_receive(&pkt, server, &mut buf)
+			.await
+			.map_err(|e| match e {
+				ConnectionError::Timeout => RequestError::NoResponse,
+				ConnectionError::IO(e) => RequestError::Unreachable(e),
+				e => e.into(),
+			})?;
 		
 		if remote.ip() != server.ip() {
 			return Err(RequestError::UnknownPeer);
 		}
-		conn.connect_to(remote).ok();
+		conn.connect_to(remote).await.ok();
 
 		match pkt {
 			TftpPacket::OAck(oack) => {
-				let opts = tftp::options::parse_tftp_options(
-					oack.options().map_err(|e| ConnectionError::from(e))?
-				)?;
-				conn.set_options(&opts[..]);
+				let raw_opts = oack.options().map_err(ConnectionError::from)?;
+				let opts = match tftp::options::validate_oack(&options, raw_opts, RequestKind::Wrq, &self.registry) {
+					Ok(opts) => opts,
+					Err(e) => {
+						conn.send_error(ErrorCode::InvalidOption, &e.to_string()).await.ok();
+						return Err(e.into());
+					},
+				};
+				conn.set_options(&opts[..], &self.registry);
 			},
-			TftpPacket::Ack(_) => (),
+			TftpPacket::Ack(_) => {
+				if !options.is_empty() {
+					info!("server ignored our options, using defaults");
+				}
+			},
+			TftpPacket::Err(e) => return Err(request_refusal(e, &options)),
 			_ => return Err(ConnectionError::UnexpectedPacket.into())
 		}
 		
-		conn.send_data(file).await?;
-		Ok(())
+		let (mut stats, digest) = match (file, local) {
+			(Some(file), _) => {
+				let mut file = HashingStream::new(file, params.digest_algorithm());
+				(conn.send_data(&mut file).await?, file.finish())
+			},
+			(None, Local::Source(source, _)) => {
+				let mut source = HashingStream::new(&mut **source, params.digest_algorithm());
+				(conn.send_data(&mut source).await?, source.finish())
+			},
+			(None, _) => unreachable!("uploads come from a file or a source"),
+		};
+		stats.digest = digest;
+		params.verify(stats.digest.as_ref())?;
+		Ok(stats)
+	}
+}
+
+///
+/// The upload's end of a relay. The download ending only ends the file if it succeeded.
+///
+struct RelayReader<'a> {
+	inner: DuplexStream,
+	download_failed: &'a AtomicBool,
+}
+impl AsyncRead for RelayReader<'_> {
+	fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+		let filled = buf.filled().len();
+		match Pin::new(&mut self.inner).poll_read(cx, buf) {
+			Poll::Ready(Ok(())) if buf.filled().len() == filled && self.download_failed.load(Ordering::Relaxed) => {
+				Poll::Ready(Err(io::Error::other("the download failed")))
+			},
+			res => res,
+		}
 	}
 }
 
-pub async fn run_client(action: cli::ClientAction, opts: cli::ClientOpts, root: PathBuf, cxl_token: CancellationToken) -> Result<()> {
-	let mut client = TftpClient::new(cxl_token);
+///
+/// A download in progress. It's written next to its destination and renamed over it
+/// once complete, so the destination is left as it was if the download fails.
+///
+struct PartialFile {
+	path: PathBuf,
+	dest: PathBuf,
+	file: std::fs::File,
+	persisted: bool,
+}
+impl PartialFile {
+	fn create(dest: &Path) -> io::Result<Self> {
+		static COUNTER: AtomicU32 = AtomicU32::new(0);
+
+		/* Opening the destination for writing would have failed as well */
+		let original = match std::fs::metadata(dest) {
+			Ok(meta) if meta.is_dir() => return Err(io::Error::new(io::ErrorKind::IsADirectory, "destination is a directory")),
+			Ok(meta) if meta.permissions().readonly() => return Err(io::ErrorKind::PermissionDenied.into()),
+			Ok(meta) => Some(meta.permissions()),
+			Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+			Err(e) => return Err(e),
+		};
 
-	let req_opts = action.options();
-	let mut file_path = root;
-	file_path.push(&req_opts.file.to_string_lossy()[..]);
+		let name = dest.file_name().ok_or(io::ErrorKind::InvalidInput)?.to_string_lossy();
+		let dir = dest.parent().unwrap_or(Path::new(""));
+		loop {
+			let nr = COUNTER.fetch_add(1, Ordering::Relaxed);
+			let path = dir.join(format!(".{}.{}-{}.part", name, std::process::id(), nr));
+			match OpenOptions::new().create_new(true).read(true).write(true).open(&path) {
+				Ok(file) => {
+					if let Some(permissions) = original {
+						file.set_permissions(permissions)?;
+					}
+					return Ok(Self { path, dest: dest.to_path_buf(), file, persisted: false });
+				},
+				Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+				Err(e) => return Err(e),
+			}
+		}
+	}
 
-	cli::parse_tftp_options(opts)
-		.iter()
-		.for_each(|opt| client.add_option(opt));
+	/// Moves the complete file into place.
+	fn persist(mut self) -> io::Result<()> {
+		self.file.sync_all()?;
+		std::fs::rename(&self.path, &self.dest)?;
+		self.persisted = true;
+		Ok(())
+	}
+}
+impl Drop for PartialFile {
+	fn drop(&mut self) {
+		if !self.persisted {
+			std::fs::remove_file(&self.path).ok();
+		}
+	}
+}
+
+fn set_option(options: &mut Vec<TftpOption>, option: TftpOption) {
+	match options.iter_mut().find(|e| e.name().eq_ignore_ascii_case(option.name())) {
+		Some(opt) => *opt = option,
+		None => options.push(option),
+	}
+}
+
+/// The next smaller blksize to probe with, following the MTUs of common links
+/// (Ethernet, PPPoE, IPv6 minimum) down to the default.
+fn next_probe_blocksize(blocksize: u16, server: &SocketAddr) -> Option<u16> {
+	[1500, 1492, 1280]
+		.into_iter()
+		.map(|mtu| utils::blocksize_for_mtu(mtu, server))
+		.chain([tftp::consts::DEFAULT_BLOCK_SIZE])
+		.find(|bs| *bs < blocksize)
+}
 
-	let server = (req_opts.server, req_opts.port).into();
-	match action.as_request_kind() {
-		RequestKind::Rrq => client.get(file_path, server).await,
-		RequestKind::Wrq => client.put(file_path, server).await
+/// Maps an ERROR sent in reply to our request. ERROR 8 only means the server refused
+/// our options when we actually sent some, which allows retrying without them.
+fn request_refusal(e: tftp::packet::TftpError<'_>, options: &[TftpOption]) -> RequestError {
+	let e = TftpError::from(e);
+	if e.is_option_refusal() && !options.is_empty() {
+		OptionError::Refused.into()
+	} else {
+		ConnectionError::PeerError(e).into()
 	}
-}
\ No newline at end of file
+}
@@ -0,0 +1,20 @@
+//!
+//! TFTP client and server (RFC 1350) with option extensions: blksize, timeout,
+//! tsize (RFC 2347-2349), utimeout and multicast (RFC 2090).
+//!
+//! The client lives in [`client`], the server in [`server`], each behind the feature
+//! of the same name. Packets can be parsed and built with [`packet`], options are
+//! handled by [`options`].
+//!
+
+/* The connection internals are shared by client and server, parts of them are unused
+ * with only one of both enabled */
+#[cfg_attr(not(all(feature = "client", feature = "server")), allow(dead_code))]
+mod tftp;
+#[cfg(feature = "server")]
+pub mod server;
+#[cfg(feature = "client")]
+pub mod client;
+
+pub use crate::tftp::{checksum, consts, error, options, packet, stats};
+pub use crate::tftp::{Mode, RequestKind};
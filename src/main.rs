@@ -1,12 +1,23 @@
 
-pub mod cli;
-pub mod tftp;
-#[cfg(feature = "server")]
-pub mod server;
+/* Without client and server there is no command to run, so nothing past parsing the arguments is reachable */
+#![cfg_attr(not(any(feature = "client", feature = "server")), allow(unreachable_code, unused_variables))]
+
+mod cli;
+/* Only the client uses these, clap needs their types for its arguments anyway */
+#[cfg_attr(not(feature = "client"), allow(dead_code))]
+mod progress;
+#[cfg_attr(not(feature = "client"), allow(dead_code))]
+mod target;
+#[cfg(feature = "client")]
+mod batch;
 #[cfg(feature = "client")]
-pub mod client;
+mod probe;
+#[cfg(feature = "client")]
+mod shell;
 
-use std::{error::Error, io, path::PathBuf};
+use std::{error::Error, io, path::PathBuf, sync::{Arc, Mutex}};
+#[cfg(any(feature = "client", feature = "server"))]
+use std::time::Duration;
 
 #[allow(unused)]
 use log::{info, warn, error, debug, trace};
@@ -14,7 +25,19 @@ use tokio_util::sync::CancellationToken;
 use clap::Parser;
 
 #[cfg(feature = "server")]
-use server::TftpServer;
+use tftp::server::{MulticastConfig, TftpServer};
+#[cfg(feature = "server")]
+use tftp::options::NegotiationPolicy;
+#[cfg(feature = "client")]
+use tftp::{client::{TftpClient, TftpRequestParameters}, RequestKind};
+#[cfg(feature = "client")]
+use tokio::io::AsyncReadExt;
+#[cfg(feature = "client")]
+use tftp::checksum::{Algorithm, Checksum};
+#[cfg(feature = "client")]
+use std::net::SocketAddr;
+#[cfg(feature = "client")]
+use target::ServerTarget;
 
 async fn run(opts: cli::Options) -> Result<(), Box<dyn Error>> {
 	/* Init our root directory */
@@ -34,38 +57,224 @@ async fn run(opts: cli::Options) -> Result<(), Box<dyn Error>> {
 	debug!("working dir '{}'", root_dir.display());
 
 	let cancel_token: CancellationToken = CancellationToken::new();
-	let sigint_token = cancel_token.clone();
+	/* The shell swaps the token after each interrupted transfer */
+	let sigint_token = Arc::new(Mutex::new(cancel_token.clone()));
+	#[cfg(feature = "client")]
+	let shell_token = sigint_token.clone();
 
 	/* Let's handle SIGINT on our own to gracefully shutdown all tasks */
 	ctrlc::set_handler(move || {
 		info!("Received SIGINT");
-		sigint_token.cancel();
+		sigint_token.lock().unwrap().cancel();
 	}).expect("Failed to install SIGINT handler");
 
 	match opts.run_mode {
 		#[cfg(feature = "server")]
-		cli::RunMode::Server { bind, port } => {
-			TftpServer::new((bind, port).into(), root_dir)?
-				.run(cancel_token)
-				.await?
+		cli::RunMode::Server { bind, port, dally, min_blocksize, max_blocksize, multicast } => {
+			let mut server = TftpServer::new((bind, port).into(), root_dir)?;
+			server.set_dally_period(dally.map(|d| Duration::from_secs(d as u64)));
+			server.set_negotiation_policy(match max_blocksize {
+				cli::Blocksize::Auto => NegotiationPolicy { min_blocksize, ..Default::default() },
+				cli::Blocksize::Fixed(max_blocksize) => NegotiationPolicy {
+					min_blocksize, max_blocksize, mtu_clamp: false, ..Default::default()
+				},
+			});
+			server.set_multicast(multicast.map(MulticastConfig::new));
+			server.run(cancel_token).await?
+		},
+		#[cfg(feature = "client")]
+		cli::RunMode::Client { client_opts, action: cli::ClientAction::Relay { from, to, remote, buffer, port } } => {
+			run_relay(client_opts, from, to, remote, buffer, port, cancel_token).await?
+		},
+		#[cfg(feature = "client")]
+		cli::RunMode::Client { client_opts, action: cli::ClientAction::Probe { server, remote, port } } => {
+			let file = match (server.file.clone(), remote) {
+				(Some(file), None) | (None, Some(file)) => file,
+				(Some(_), Some(_)) => return Err(io::Error::new(io::ErrorKind::InvalidInput, "too many files given along with a URI").into()),
+				(None, None) => return Err(io::Error::new(io::ErrorKind::InvalidInput, "REMOTE is required unless SERVER is a URI").into()),
+			};
+			probe::run(server, file, port, client_opts.timeout, cancel_token).await?
+		},
+		#[cfg(feature = "client")]
+		cli::RunMode::Client { client_opts, action: cli::ClientAction::Shell { server, port } } => {
+			shell::run(client_opts, server, port, root_dir, shell_token).await?
+		},
+		#[cfg(feature = "client")]
+		cli::RunMode::Client { client_opts, action: cli::ClientAction::Batch { manifest, jobs, port } } => {
+			batch::run(client_opts, manifest, jobs, port, root_dir, cancel_token).await?
 		},
 		#[cfg(feature = "client")]
 		cli::RunMode::Client { client_opts, action } => {
-			client::run_client(action, client_opts, root_dir, cancel_token).await?
+			run_client(action, client_opts, root_dir, cancel_token).await?
 		},
 	};
 
 	Ok(())
 }
 
+#[cfg(feature = "client")]
+async fn run_client(action: cli::ClientAction, opts: cli::ClientOpts, root: PathBuf, cxl_token: CancellationToken) -> tftp::client::Result<()> {
+	let mut client = TftpClient::new(cxl_token);
+	let kind = action.as_request_kind();
+
+	let req_opts = action.options();
+	let (local, remote) = action.files().map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+	let (checksum, sidecar) = match &action {
+		cli::ClientAction::Get { checksum, sidecar, .. } => (checksum.clone(), *sidecar),
+		_ => (None, None),
+	};
+	let local_name = local.display().to_string();
+	let stdio = cli::is_stdio(&local);
+	let file_path = root.join(local);
+	let buffer_stdin = opts.buffer_stdin;
+
+	client.set_dally_period(opts.dally.map(|d| Duration::from_secs(d as u64)));
+	client.set_auto_blocksize(opts.blocksize == cli::Blocksize::Auto);
+	client.set_blocksize_probing(opts.probe_blocksize);
+	client.set_retry_policy(cli::retry_policy(&opts, 0));
+	let reporter = match opts.quiet {
+		true => None,
+		false => progress::ProgressReporter::new(opts.progress, stdio && kind == RequestKind::Rrq).map(Arc::new),
+	};
+	/* Requesting the timeout isn't enough, it also has to apply before the server answers */
+	let timeout = opts.timeout;
+	cli::parse_tftp_options(opts)
+		.iter()
+		.for_each(|opt| client.add_option(opt));
+
+	let servers = req_opts.server.resolve(req_opts.port).await?;
+	let mut stdin_data: Option<Vec<u8>> = None;
+	if stdio && kind == RequestKind::Wrq && buffer_stdin {
+		let mut data: Vec<u8> = Vec::new();
+		tokio::io::stdin().read_to_end(&mut data).await?;
+		stdin_data = Some(data);
+	}
+
+	let res = target::try_each(servers, |server| {
+		let mut params = TftpRequestParameters::new(kind, server, file_path.clone())
+			.remote(&remote)
+			.mode(req_opts.server.mode.unwrap_or(tftp::Mode::Octet))
+			.timeout(timeout);
+		if let Some(reporter) = reporter.as_ref() {
+			params = params.progress(reporter.clone());
+		}
+		if let Some(checksum) = checksum.clone() {
+			params = params.checksum(checksum);
+		}
+		if let Some(algorithm) = req_opts.digest {
+			params = params.digest(algorithm);
+		}
+		let (client, stdin_data, remote) = (&client, stdin_data.as_ref(), &remote);
+		async move {
+			if let Some(algorithm) = sidecar {
+				params = params.checksum(fetch_sidecar(client, server, remote, algorithm).await?);
+			}
+			match (kind, stdio, stdin_data) {
+				(_, false, _) => client.request(&params).await,
+				(RequestKind::Rrq, true, _) => client.get_to(&params, tokio::io::stdout()).await,
+				(RequestKind::Wrq, true, Some(data)) => client.put_bytes(&params, data).await,
+				/* Without knowing the size up front, tsize is left out */
+				(RequestKind::Wrq, true, None) => client.put_from(&params, tokio::io::stdin(), None).await,
+			}
+		}
+	}).await;
+	if let Some(reporter) = reporter.as_ref() {
+		reporter.finish();
+	}
+	let stats = res?;
+	info!("transfer finished: {}", stats);
+	if let (Some(digest), true) = (stats.digest.as_ref(), checksum.is_some() || sidecar.is_some()) {
+		info!("{} verified", digest);
+	}
+	if let (Some(digest), Some(_)) = (stats.digest.as_ref(), req_opts.digest) {
+		/* Like sha256sum prints it, on stderr if stdout carries the file */
+		match stdio && kind == RequestKind::Rrq {
+			true => eprintln!("{}  {}", digest.to_hex(), local_name),
+			false => println!("{}  {}", digest.to_hex(), local_name),
+		}
+	}
+	Ok(())
+}
+
+#[cfg(feature = "client")]
+async fn run_relay(
+	opts: cli::ClientOpts,
+	from: ServerTarget,
+	to: ServerTarget,
+	remote: Option<String>,
+	buffer: usize,
+	port: u16,
+	cxl_token: CancellationToken,
+) -> tftp::client::Result<()> {
+	let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidInput, msg.to_string());
+	let source = match (from.file.as_ref(), remote) {
+		(Some(_), Some(_)) => return Err(invalid("too many files given along with a URI").into()),
+		(Some(file), None) => file.clone(),
+		(None, Some(remote)) => remote,
+		(None, None) => return Err(invalid("name the file on the source server, or give FROM as a URI").into()),
+	};
+	let dest = to.file.clone().unwrap_or_else(|| source.clone());
+
+	let mut client = TftpClient::new(cxl_token);
+	client.set_dally_period(opts.dally.map(|d| Duration::from_secs(d as u64)));
+	client.set_auto_blocksize(opts.blocksize == cli::Blocksize::Auto);
+	let reporter = match opts.quiet {
+		true => None,
+		false => progress::ProgressReporter::new(opts.progress, false).map(Arc::new),
+	};
+	let timeout = opts.timeout;
+	cli::parse_tftp_options(opts)
+		.iter()
+		.for_each(|opt| client.add_option(opt));
+
+	let sources = from.resolve(port).await?;
+	let dests = to.resolve(port).await?;
+	let res = target::try_each(sources, |src| {
+		let mut from_params = TftpRequestParameters::new(RequestKind::Rrq, src, PathBuf::from(&source))
+			.remote(&source)
+			.mode(from.mode.unwrap_or(tftp::Mode::Octet))
+			.timeout(timeout);
+		if let Some(reporter) = reporter.as_ref() {
+			from_params = from_params.progress(reporter.clone());
+		}
+		let (client, dests, dest, mode) = (&client, dests.clone(), &dest, to.mode.or(from.mode));
+		target::try_each(dests, move |dst| {
+			let from_params = from_params.clone();
+			let to_params = TftpRequestParameters::new(RequestKind::Wrq, dst, PathBuf::from(dest))
+				.remote(dest)
+				.mode(mode.unwrap_or(tftp::Mode::Octet))
+				.timeout(timeout);
+			async move { client.relay(&from_params, &to_params, buffer).await }
+		})
+	}).await;
+	if let Some(reporter) = reporter.as_ref() {
+		reporter.finish();
+	}
+	let (received, sent) = res?;
+	info!("received '{}' from {}: {}", source, received.peer, received);
+	info!("sent '{}' to {}: {}", dest, sent.peer, sent);
+	Ok(())
+}
+
+/// Downloads the digest of `remote` from the sidecar file next to it, e.g. `REMOTE.sha256`.
+#[cfg(feature = "client")]
+async fn fetch_sidecar(client: &TftpClient, server: SocketAddr, remote: &str, algorithm: Algorithm) -> tftp::client::Result<Checksum> {
+	let sidecar = format!("{}.{}", remote, algorithm.extension());
+	let params = TftpRequestParameters::new(RequestKind::Rrq, server, PathBuf::from(&sidecar)).remote(&sidecar);
+	let (content, _) = client.get_vec(&params).await?;
+	let content = String::from_utf8_lossy(&content);
+	Checksum::from_sidecar(algorithm, &content)
+		.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", sidecar, e)).into())
+}
+
 #[tokio::main]
 async fn main() {
 	let options = cli::Options::parse();
 
 	cli::init_logger(options.debug);
 
-	match run(options).await {
-		Ok(_) => (),
-		Err(e) => error!("Error: {e}"),
+	if let Err(e) = run(options).await {
+		error!("Error: {e}");
+		std::process::exit(1);
 	}
 }
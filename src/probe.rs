@@ -0,0 +1,292 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+#[allow(unused)]
+use log::{info, warn, error, debug, trace};
+use tokio::net::UdpSocket;
+use tokio_util::sync::CancellationToken;
+
+use tftp::consts;
+use tftp::error::{ErrorCode, RequestError};
+use tftp::packet::builder::{TftpErrorBuilder, TftpReqBuilder};
+use tftp::packet::{Packet, TftpPacket};
+use tftp::{Mode, RequestKind};
+
+use crate::target::{self, ServerTarget};
+
+/// Requests are sent this often before giving up on a probe.
+const ATTEMPTS: u32 = 3;
+/// Large enough for DATA with the largest blksize, should a server skip the OACK.
+const RECV_BUF_SIZE: usize = 4 + consts::MAX_BLOCK_SIZE as usize;
+
+///
+/// A single RRQ sent to find out how the server reacts to it.
+///
+struct Probe {
+	name: String,
+	file: String,
+	mode: Mode,
+	options: Vec<(&'static str, String)>,
+}
+impl Probe {
+	fn new(file: &str, options: &[(&'static str, &str)]) -> Self {
+		let name = match options {
+			[] => "plain request".to_string(),
+			options => options.iter().map(|(name, value)| format!("{}={}", name, value)).collect::<Vec<_>>().join(" "),
+		};
+		Self {
+			name,
+			file: file.to_string(),
+			mode: Mode::Octet,
+			options: options.iter().map(|(name, value)| (*name, value.to_string())).collect(),
+		}
+	}
+}
+
+/// What the server replied to a probe.
+enum Reply {
+	/// The options acknowledged, in the order sent.
+	OAck(Vec<(String, String)>),
+	/// Options, if any, were ignored and the transfer started right away.
+	Data(usize),
+	Error(ErrorCode, String),
+	Invalid(String),
+	None,
+}
+
+struct Outcome {
+	probe: Probe,
+	from: Option<SocketAddr>,
+	reply: Reply,
+}
+
+///
+/// Sends the server a series of RRQs for `file`: without options, in netascii mode, with
+/// each option on its own and with all of them, and for a file that doesn't exist.
+/// Transfers are aborted after the first reply. Prints what the server replied to each
+/// and a summary of what it supports. Of several addresses the first one answering the
+/// plain request is probed.
+///
+pub async fn run(server: ServerTarget, file: String, port: u16, timeout: Duration, cxl_token: CancellationToken) -> io::Result<()> {
+	let addrs = server.resolve(port).await?;
+	let (addr, outcomes) = target::try_each(addrs, |addr| {
+		let (file, cxl_token) = (&file, &cxl_token);
+		async move {
+			let outcomes = probe_all(file, addr, timeout, cxl_token).await?;
+			Ok((addr, outcomes))
+		}
+	}).await.map_err(|e| match e {
+		RequestError::OtherHostError(e) => e,
+		e => io::Error::other(e),
+	})?;
+
+	print_report(addr, &file, &outcomes);
+	Ok(())
+}
+
+/// Runs all probes against `addr`, unless it doesn't reply to the plain request.
+async fn probe_all(file: &str, addr: SocketAddr, timeout: Duration, cxl_token: &CancellationToken) -> tftp::client::Result<Vec<Outcome>> {
+	let min_blocksize = consts::MIN_BLOCK_SIZE.to_string();
+	let max_blocksize = consts::MAX_BLOCK_SIZE.to_string();
+	let mut probes = vec![
+		Probe::new(file, &[]),
+		Probe { name: "netascii".to_string(), mode: Mode::NetAscii, ..Probe::new(file, &[]) },
+		Probe::new(file, &[(consts::OPT_BLOCKSIZE_IDENT, "1428")]),
+		Probe::new(file, &[(consts::OPT_BLOCKSIZE_IDENT, &min_blocksize)]),
+		Probe::new(file, &[(consts::OPT_BLOCKSIZE_IDENT, &max_blocksize)]),
+		Probe::new(file, &[(consts::OPT_TIMEOUT_IDENT, "1")]),
+		Probe::new(file, &[(consts::OPT_UTIMEOUT_IDENT, "500000")]),
+		Probe::new(file, &[(consts::OPT_TRANSFERSIZE_IDENT, "0")]),
+		Probe::new(file, &[(consts::OPT_WINDOWSIZE_IDENT, "4")]),
+		Probe::new(file, &[(consts::OPT_MULTICAST_IDENT, "")]),
+		Probe::new(file, &[
+			(consts::OPT_BLOCKSIZE_IDENT, "1428"),
+			(consts::OPT_TIMEOUT_IDENT, "1"),
+			(consts::OPT_TRANSFERSIZE_IDENT, "0"),
+			(consts::OPT_WINDOWSIZE_IDENT, "4"),
+		]),
+	];
+	let missing = format!("tftp-probe-{}.missing", std::process::id());
+	probes.push(Probe { name: "missing file".to_string(), ..Probe::new(&missing, &[]) });
+
+	let mut outcomes: Vec<Outcome> = Vec::with_capacity(probes.len());
+	for probe in probes {
+		if cxl_token.is_cancelled() {
+			return Err(io::Error::new(io::ErrorKind::Interrupted, "probing was cancelled").into());
+		}
+		let (from, reply) = send_probe(&probe, addr, timeout, cxl_token).await.map_err(|e| match e.kind() {
+			io::ErrorKind::Interrupted => e.into(),
+			_ if outcomes.is_empty() => RequestError::Unreachable(e),
+			_ => e.into(),
+		})?;
+		debug!("{}: {}", probe.name, describe(&reply));
+		if outcomes.is_empty() && matches!(reply, Reply::None) {
+			return Err(RequestError::NoResponse);
+		}
+		outcomes.push(Outcome { probe, from, reply });
+	}
+	Ok(outcomes)
+}
+
+/// Sends the request, waits for the first reply and aborts the transfer it started.
+async fn send_probe(
+	probe: &Probe,
+	server: SocketAddr,
+	timeout: Duration,
+	cxl_token: &CancellationToken
+) -> io::Result<(Option<SocketAddr>, Reply)> {
+	let local: IpAddr = match server {
+		SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+		SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+	};
+	let socket = UdpSocket::bind((local, 0)).await?;
+
+	let mut builder = TftpReqBuilder::new()
+		.kind(RequestKind::Rrq)
+		.mode(probe.mode)
+		.filename(&probe.file);
+	for (name, value) in probe.options.iter() {
+		builder = builder.raw_option(name, value);
+	}
+	let request = builder.build();
+
+	let mut buf = vec![0u8; RECV_BUF_SIZE];
+	for _ in 0..ATTEMPTS {
+		socket.send_to(request.as_bytes(), server).await?;
+		let received = loop {
+			let res = tokio::select! {
+				res = tokio::time::timeout(timeout, socket.recv_from(&mut buf)) => res,
+				_ = cxl_token.cancelled() => return Err(io::Error::new(io::ErrorKind::Interrupted, "probing was cancelled")),
+			};
+			match res {
+				/* Replies come from a new port, but must come from the server */
+				Ok(Ok((_, from))) if from.ip() != server.ip() => continue,
+				Ok(Ok(received)) => break Some(received),
+				Ok(Err(e)) => return Err(e),
+				Err(_) => break None,
+			}
+		};
+		let Some((len, from)) = received else {
+			continue;
+		};
+
+		let reply = match TftpPacket::try_from_buf(&buf[..len]) {
+			Ok(TftpPacket::OAck(oack)) => match oack.options() {
+				Ok(acked) => {
+					/* Keep the order of the request, anything unrequested goes last */
+					let mut acked: Vec<(String, String)> = acked
+						.into_iter()
+						.map(|(name, value)| (name.to_ascii_lowercase(), value.to_string()))
+						.collect();
+					acked.sort_by_key(|(name, _)| probe.options.iter().position(|(n, _)| n == name).unwrap_or(usize::MAX));
+					Reply::OAck(acked)
+				},
+				Err(e) => Reply::Invalid(format!("malformed OACK: {}", e)),
+			},
+			Ok(TftpPacket::Data(data)) => Reply::Data(data.data_len()),
+			Ok(TftpPacket::Err(e)) => Reply::Error(e.error_code(), e.error_msg().to_string()),
+			Ok(other) => Reply::Invalid(format!("unexpected {}", other.packet_kind())),
+			Err(e) => Reply::Invalid(format!("malformed packet: {}", e)),
+		};
+		if matches!(reply, Reply::OAck(_) | Reply::Data(_)) {
+			let abort = TftpErrorBuilder::new()
+				.error_code(ErrorCode::NotDefined)
+				.error_msg("probe finished")
+				.build();
+			socket.send_to(abort.as_bytes(), from).await.ok();
+		}
+		return Ok((Some(from), reply));
+	}
+	Ok((None, Reply::None))
+}
+
+fn describe(reply: &Reply) -> String {
+	match reply {
+		Reply::OAck(options) if options.is_empty() => "OACK without options".to_string(),
+		Reply::OAck(options) => {
+			let options: Vec<String> = options.iter().map(|(name, value)| format!("{}={}", name, value)).collect();
+			format!("OACK {}", options.join(" "))
+		},
+		Reply::Data(len) => format!("DATA block 1 ({} bytes)", len),
+		Reply::Error(code, msg) => format!("ERROR {} '{}'", code, msg),
+		Reply::Invalid(msg) => msg.clone(),
+		Reply::None => "no reply".to_string(),
+	}
+}
+
+fn print_report(server: SocketAddr, file: &str, outcomes: &[Outcome]) {
+	println!("Probed {} with '{}'", server, file);
+	println!();
+
+	let width = outcomes.iter().map(|o| o.probe.name.len()).max().unwrap_or(0);
+	for o in outcomes.iter() {
+		let port = match o.from {
+			Some(from) if from.port() == server.port() => " (from the listening port)",
+			_ => "",
+		};
+		println!("  {:<width$}  {}{}", o.probe.name, describe(&o.reply), port, width = width);
+	}
+	println!();
+
+	let Some(plain) = outcomes.first() else {
+		return;
+	};
+	match &plain.reply {
+		Reply::Data(_) | Reply::OAck(_) => println!("Reading '{}': ok", file),
+		reply => {
+			println!("Reading '{}': {}", file, describe(reply));
+			println!("Options can only be probed with a file the server lets us read.");
+			return;
+		},
+	}
+
+	let netascii = outcomes.iter().find(|o| o.probe.mode == Mode::NetAscii);
+	if let Some(o) = netascii {
+		let supported = matches!(o.reply, Reply::Data(_) | Reply::OAck(_));
+		println!("netascii mode: {}", if supported { "supported" } else { "not supported" });
+	}
+
+	/* Probes with a single option tell which options the server knows */
+	let single: Vec<&Outcome> = outcomes.iter().filter(|o| o.probe.options.len() == 1).collect();
+	let mut names: Vec<&str> = Vec::new();
+	for o in single.iter() {
+		if !names.contains(&o.probe.options[0].0) {
+			names.push(o.probe.options[0].0);
+		}
+	}
+	println!("Options:");
+	for name in names {
+		let results: Vec<String> = single
+			.iter()
+			.filter(|o| o.probe.options[0].0 == name)
+			.map(|o| {
+				let requested = &o.probe.options[0].1;
+				let result = match &o.reply {
+					Reply::OAck(acked) => match acked.iter().find(|(n, _)| n == name) {
+						Some((_, value)) if value == requested => "granted".to_string(),
+						/* The server fills in the size of the file, RFC 2349 */
+						Some((_, value)) if name == consts::OPT_TRANSFERSIZE_IDENT => format!("granted (size {})", value),
+						Some((_, value)) => format!("answered with {}", value),
+						None => "ignored".to_string(),
+					},
+					Reply::Data(_) => "ignored".to_string(),
+					Reply::Error(code, _) => format!("refused with ERROR {}", code),
+					reply => describe(reply),
+				};
+				match requested.is_empty() {
+					true => result,
+					false => format!("{}: {}", requested, result),
+				}
+			})
+			.collect();
+		println!("  {:<10}  {}", name, results.join(", "));
+	}
+
+	if let Some(o) = outcomes.iter().find(|o| o.probe.options.len() > 1) {
+		println!("All options at once: {}", describe(&o.reply));
+	}
+	if let Some(o) = outcomes.iter().find(|o| o.probe.file != file) {
+		println!("Missing files: {}", describe(&o.reply));
+	}
+}
@@ -0,0 +1,145 @@
+use std::io::{self, IsTerminal, Write};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use tftp::stats::ProgressObserver;
+
+/// How often the progress is printed at most.
+const UPDATE_INTERVAL: Duration = Duration::from_millis(200);
+const BAR_WIDTH: usize = 30;
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum ProgressMode {
+	/// A bar with rate and ETA, redrawn in place on stderr.
+	Bar,
+	/// One JSON object per line on stdout, for scripts. On stderr if stdout carries the file.
+	Json,
+}
+
+#[derive(Debug)]
+struct State {
+	last_update: Option<Instant>,
+	/// When the last block was transferred, so lingering afterwards doesn't count.
+	last_block: Instant,
+	transferred: u64,
+	total: Option<u64>,
+}
+
+///
+/// Prints the progress of a single transfer to the terminal.
+///
+#[derive(Debug)]
+pub struct ProgressReporter {
+	mode: ProgressMode,
+	/// stdout is taken by the file being downloaded.
+	stdout_busy: bool,
+	started: Instant,
+	state: Mutex<State>,
+}
+
+impl ProgressReporter {
+	/// Without an explicit mode the bar is only drawn if stderr is a terminal.
+	pub fn new(mode: Option<ProgressMode>, stdout_busy: bool) -> Option<Self> {
+		let mode = match mode {
+			Some(mode) => mode,
+			None if io::stderr().is_terminal() => ProgressMode::Bar,
+			None => return None,
+		};
+		Some(Self {
+			mode,
+			stdout_busy,
+			started: Instant::now(),
+			state: Mutex::new(State { last_update: None, last_block: Instant::now(), transferred: 0, total: None }),
+		})
+	}
+
+	/// Prints the final state, regardless of when the last update was printed.
+	pub fn finish(&self) {
+		let state = self.state.lock().unwrap();
+		self.print(&state, true);
+	}
+
+	fn print(&self, state: &State, done: bool) {
+		let elapsed = state.last_block.duration_since(self.started).as_secs_f64();
+		let rate = match elapsed {
+			secs if secs > 0.0 => state.transferred as f64 / secs,
+			_ => 0.0,
+		};
+		let eta = match state.total {
+			Some(total) if rate > 0.0 => Some(total.saturating_sub(state.transferred) as f64 / rate),
+			_ => None,
+		};
+
+		match self.mode {
+			ProgressMode::Bar => {
+				let mut line = match state.total {
+					Some(total) if total > 0 => {
+						let ratio = (state.transferred as f64 / total as f64).min(1.0);
+						let filled = (ratio * BAR_WIDTH as f64) as usize;
+						format!(
+							"[{}{}] {:3.0}% {}/{}",
+							"#".repeat(filled), "-".repeat(BAR_WIDTH - filled),
+							ratio * 100.0, human_size(state.transferred), human_size(total),
+						)
+					},
+					_ => human_size(state.transferred),
+				};
+				line.push_str(&format!("  {}/s", human_size(rate as u64)));
+				if let (Some(eta), false) = (eta, done) {
+					line.push_str(&format!("  ETA {}:{:02}", eta as u64 / 60, eta as u64 % 60));
+				}
+
+				let mut stderr = io::stderr().lock();
+				write!(stderr, "\r\x1b[K{}", line).ok();
+				if done {
+					writeln!(stderr).ok();
+				}
+				stderr.flush().ok();
+			},
+			ProgressMode::Json => {
+				let opt = |v: Option<String>| v.unwrap_or_else(|| "null".to_string());
+				let line = format!(
+					"{{\"transferred\":{},\"total\":{},\"elapsed\":{:.3},\"rate\":{:.0},\"eta\":{},\"done\":{}}}",
+					state.transferred,
+					opt(state.total.map(|t| t.to_string())),
+					elapsed,
+					rate,
+					opt(eta.map(|e| format!("{:.1}", e))),
+					done,
+				);
+				match self.stdout_busy {
+					true => eprintln!("{}", line),
+					false => println!("{}", line),
+				}
+			},
+		}
+	}
+}
+
+impl ProgressObserver for ProgressReporter {
+	fn progress(&self, transferred: u64, total: Option<u64>) {
+		let mut state = self.state.lock().unwrap();
+		state.transferred = transferred;
+		state.total = total;
+		state.last_block = Instant::now();
+		if state.last_update.is_some_and(|t| t.elapsed() < UPDATE_INTERVAL) {
+			return;
+		}
+		state.last_update = Some(Instant::now());
+		self.print(&state, false);
+	}
+}
+
+fn human_size(bytes: u64) -> String {
+	const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+	if bytes < 1024 {
+		return format!("{} B", bytes);
+	}
+	let mut size = bytes as f64 / 1024.0;
+	let mut unit = 0;
+	while size >= 1024.0 && unit < UNITS.len() - 1 {
+		size /= 1024.0;
+		unit += 1;
+	}
+	format!("{:.1} {}", size, UNITS[unit])
+}
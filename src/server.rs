@@ -1,20 +1,21 @@
-use std::error::Error;
-use std::io;
-use std::net::{UdpSocket, SocketAddr, IpAddr};
-use std::fs::OpenOptions;
-use std::path::PathBuf;
-use std::time::Duration;
-use std::collections::HashMap;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::net::{UdpSocket, SocketAddr, SocketAddrV4, IpAddr, Ipv4Addr};
+use std::fs::{File, OpenOptions};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
+use std::collections::{HashMap, HashSet, VecDeque};
 
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio_util::sync::CancellationToken;
 
 #[allow(unused)]
 use log::{info, warn, error, debug, trace};
 
-use crate::tftp::error::{ConnectionError, ErrorCode, OptionError, RequestError};
-use crate::tftp::{RequestKind, TftpConnection};
-use crate::tftp::options::{parse_tftp_options, TftpOption, TftpOptionKind};
-use crate::tftp::packet as pkt;
+use crate::tftp::error::{ConnectionError, ErrorCode, OptionError, RequestError, TftpError};
+use crate::tftp::{consts, multicast, RequestKind, TftpConnection};
+use crate::tftp::options::{self, NegotiationPolicy, TftpOption, TftpOptionKind};
+use crate::tftp::packet::{self as pkt, Packet};
 
 // ############################################################################
 // ############################################################################
@@ -22,10 +23,13 @@ use crate::tftp::packet as pkt;
 
 pub type Result<T> = std::result::Result<T, RequestError>;
 
-pub struct TftpRequestHandler {
+struct TftpRequestHandler {
 	listen_addr: IpAddr,
 	cancel_token: CancellationToken,
-	root: PathBuf,
+	storage: Arc<dyn TftpStorage>,
+	dally: Option<Duration>,
+	policy: NegotiationPolicy,
+	multicast: Option<MulticastSessions>,
 }
 
 // ############################################################################
@@ -34,28 +38,84 @@ pub struct TftpRequestHandler {
 
 impl TftpRequestHandler {
 
-	pub fn new(local_ip: IpAddr, root: PathBuf, cancel_token: CancellationToken) -> Self {
+	pub fn new(local_ip: IpAddr, storage: Arc<dyn TftpStorage>, cancel_token: CancellationToken) -> Self {
 		TftpRequestHandler { 
 			listen_addr: local_ip,
 			cancel_token,
-			root
+			storage,
+			dally: None,
+			policy: NegotiationPolicy::default(),
+			multicast: None,
+		}
+	}
+
+	pub fn set_dally_period(&mut self, dally: Option<Duration>) {
+		self.dally = dally
+	}
+	pub fn set_negotiation_policy(&mut self, policy: NegotiationPolicy) {
+		self.policy = policy
+	}
+	pub fn set_multicast_sessions(&mut self, sessions: Option<MulticastSessions>) {
+		self.multicast = sessions
+	}
+
+	/// Hands an RRQ asking for the multicast option over to the session sending the
+	/// file. Returns false if the client has to be served via unicast instead.
+	fn join_multicast(
+		&self,
+		conn: &TftpConnection,
+		raw_opts: &HashMap<&str, &str>,
+		filename: &str,
+		transfer_size: Option<u32>
+	) -> Result<bool> {
+		let Some(sessions) = self.multicast.as_ref() else {
+			return Ok(false);
+		};
+		let (Some(path), Some(transfer_size)) = (self.storage.local_path(filename), transfer_size) else {
+			return Ok(false);
+		};
+		let IpAddr::V4(local) = self.listen_addr else {
+			return Ok(false);
+		};
+
+		let mut options = self.policy.negotiate(raw_opts, conn.mtu_blocksize(), RequestKind::Rrq).options;
+		/* The session paces itself, a window of blocks only applies to unicast */
+		options.retain(|e| e.kind() != TftpOptionKind::WindowSize);
+		if let Some(tf_size) = options.iter_mut().find(|e| e.kind() == TftpOptionKind::TransferSize) {
+			*tf_size = TftpOption::TransferSize(transfer_size);
 		}
+		sessions.join(&path, conn.peer(), options, local, &self.cancel_token)
 	}
 
+	/// Returns the OACK sent to the client, if any. For RRQ the client has already
+	/// acknowledged it; for WRQ the first DATA block acknowledges it, so it is handed
+	/// to `receive_data` for retransmission.
 	async fn negotiate_options<'a>(&self,
 		conn: &mut TftpConnection,
 		raw_opts: HashMap<&'a str, &'a str>,
-		transfer_size: u32,
+		transfer_size: Option<u32>,
 		req_kind: RequestKind
-	) -> Result<bool> {
-		if raw_opts.is_empty() {
-			return Ok(false);
+	) -> Result<Option<pkt::TftpOAck<'static>>> {
+		let negotiation = self.policy.negotiate(&raw_opts, conn.mtu_blocksize(), req_kind);
+		if !negotiation.unknown.is_empty() {
+			debug!("unknown options requested by {}: {:?}", conn.peer(), negotiation.unknown);
+			conn.set_unknown_options(negotiation.unknown);
 		}
 
-		let mut requested_options = parse_tftp_options(raw_opts)?;
+		let mut requested_options = negotiation.options;
+		if req_kind == RequestKind::Rrq && transfer_size.is_none() {
+			/* Can't tell the client the size of a file we don't know the size of */
+			requested_options.retain(|e| e.kind() != TftpOptionKind::TransferSize);
+		}
+		if requested_options.is_empty() {
+			if !raw_opts.is_empty() {
+				debug!("none of the options requested by {} acceptable: {:?}", conn.peer(), raw_opts);
+			}
+			return Ok(None);
+		}
 
 		// Set transfer size if client requested it
-		if req_kind == RequestKind::Rrq {
+		if let (RequestKind::Rrq, Some(transfer_size)) = (req_kind, transfer_size) {
 			if let Some(tf_size) = requested_options.iter_mut().find(|e| e.kind() == TftpOptionKind::TransferSize) {
 				*tf_size = TftpOption::TransferSize(transfer_size);
 			}
@@ -67,20 +127,18 @@ impl TftpRequestHandler {
 			::new()
 			.options(&requested_options[..])
 			.build();
-		conn.send_packet(&oack_pkt)?;
-
-		if req_kind == RequestKind::Rrq {
-			let mut buf: [u8; 16] = [0; 16];
+		conn.set_options(&requested_options[..], &self.policy.registry);
 
-			match conn.receive_packet(&mut buf[..]) {
-				Ok(pkt::TftpPacket::Ack(_)) => (),
-				Ok(_) => return Err(OptionError::NoAck.into()),
-				Err(e) => return Err(e.into())
-			}
+		match req_kind {
+			RequestKind::Rrq => match conn.send_and_receive_ack(&oack_pkt, 0).await {
+				Ok(()) => (),
+				Err(ConnectionError::PeerError(e)) => return Err(option_refusal(conn, e)),
+				Err(ConnectionError::Timeout) => return Err(OptionError::NoAck.into()),
+				Err(e) => return Err(e.into()),
+			},
+			RequestKind::Wrq => conn.send_packet(&oack_pkt).await?,
 		}
-		
-		conn.set_options(&requested_options[..]);
-		Ok(true)
+		Ok(Some(oack_pkt))
 	}
 
 	pub async fn handle_request<'a>(&self, req: pkt::TftpReq<'a>, client: SocketAddr) -> Result<()> {
@@ -88,121 +146,587 @@ impl TftpRequestHandler {
 			self.listen_addr,
 			self.cancel_token.clone()
 		)?;
-		conn.connect_to(client)?;
+		conn.connect_to(client).await?;
 
 		match req.mode() {
-			Ok(mode) => conn.set_tx_mode(mode)?, 
+			Ok(mode) => conn.set_tx_mode(mode).await?,
 			Err(_) => {
-				conn.send_error(ErrorCode::NotDefined, "Malformed request; invalid mode").ok();
+				conn.send_error(ErrorCode::NotDefined, "Malformed request; invalid mode").await.ok();
 				return Err(RequestError::MalformedRequest);
 			},
 		}
 	
-		let mut path = self.root.clone();
 		let Ok(filename) = req.filename() else {
-			conn.send_error(ErrorCode::NotDefined, "Malformed request; missing filename").ok();
+			conn.send_error(ErrorCode::NotDefined, "Malformed request; missing filename").await.ok();
 			return Err(RequestError::MalformedRequest);
 		};
-		path.push(filename);
 
-		let mut file_opts = OpenOptions::new();
-		match req.kind() {
-			RequestKind::Rrq => file_opts.read(true),
-			RequestKind::Wrq => file_opts.create(true).truncate(true).write(true),
+		let file = match req.kind() {
+			RequestKind::Rrq => self.storage.open_read(filename).map(|(reader, size)| (LocalFile::Reader(reader), size)),
+			RequestKind::Wrq => self.storage.open_write(filename).map(|writer| (LocalFile::Writer(writer), None)),
 		};
-
-		let file = match file_opts.open(&path) {
+		let (file, file_len) = match file {
 			Ok(f) => f,
 			Err(e) if e.kind() == io::ErrorKind::NotFound => {
-				conn.send_error(ErrorCode::FileNotFound, "").ok();
+				conn.send_error(ErrorCode::FileNotFound, "").await.ok();
 				return Err(RequestError::FileNotFound);
 			},
 			Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
-				conn.send_error(ErrorCode::AccessViolation, "").ok();
+				conn.send_error(ErrorCode::AccessViolation, "").await.ok();
 				return Err(RequestError::FileNotAccessible);
 			},
 			Err(e) => {
-				conn.send_error(ErrorCode::StorageError, e.to_string().as_str()).ok();
+				conn.send_error(ErrorCode::StorageError, e.to_string().as_str()).await.ok();
 				return Err(RequestError::OtherHostError(e));
 			},
 		};
-		let file_len = match req.kind() {
-			RequestKind::Wrq => 0,
-			RequestKind::Rrq => file.metadata()?.len() as u32,
-		};
+		let file_len = file_len.and_then(|e| u32::try_from(e).ok());
 
 		/* Read, parse and acknowledge/reject options requested by the client. */
-		if self.negotiate_options(
-			&mut conn, 
-			req.options().map_err(|e| ConnectionError::from(e))?, 
-			file_len, 
-			req.kind()
-		).await? == false {
+		let raw_opts = match req.options() {
+			Ok(opts) => opts,
+			Err(e) => {
+				conn.send_error(ErrorCode::InvalidOption, "Malformed options").await.ok();
+				return Err(ConnectionError::from(e).into());
+			},
+		};
+		if req.kind() == RequestKind::Rrq && options::get_option(&raw_opts, consts::OPT_MULTICAST_IDENT).is_some() {
+			match self.join_multicast(&conn, &raw_opts, filename, file_len) {
+				Ok(true) => {
+					info!("{:?} from {} joined multicast session", req.kind(), conn.peer());
+					return Ok(());
+				},
+				Ok(false) => (),
+				/* Still serve the client, it'd otherwise keep retrying a request nobody answers */
+				Err(e) => warn!("could not start a multicast session for {}: {}", conn.peer(), e),
+			}
+			debug!("serving {} via unicast instead of multicast", conn.peer());
+		}
+
+		let oack = self.negotiate_options(&mut conn, raw_opts, file_len, req.kind()).await?;
+		if oack.is_none() {
 			if req.kind() == RequestKind::Wrq {
 				let wrq_ack = pkt::MutableTftpAck::new(0);
-				conn.send_packet(&wrq_ack)?;
+				conn.send_packet(&wrq_ack).await?;
 			}
 			conn.set_reply_timeout(conn.opt_timeout());
 		}
+		/* Our final ACK of an upload may get lost, so stay around for the client's retransmission */
+		conn.set_dally_period(Some(self.dally.unwrap_or(conn.opt_timeout())));
 	
 		info!("{:?} from {}", req.kind(), conn.peer());
-		match req.kind() {
-			RequestKind::Rrq => conn.send_data(file).await?,
-			RequestKind::Wrq => conn.receive_data(file, None).await?,
+		let stats = match file {
+			LocalFile::Reader(reader) => conn.send_data(reader).await?,
+			LocalFile::Writer(writer) => {
+				let init_reply = oack.as_ref().map(|p| p as &(dyn pkt::Packet + Sync));
+				match conn.receive_data(writer, None, init_reply).await {
+					Ok(stats) => stats,
+					Err(ConnectionError::PeerError(e)) if oack.is_some() => return Err(option_refusal(&conn, e)),
+					Err(e) => return Err(e.into()),
+				}
+			},
 		};
+		info!("{} '{}' {} {}: {}", req.kind(), filename, if req.kind() == RequestKind::Rrq { "to" } else { "from" }, stats.peer, stats);
 		Ok(())
 	}
 }
 
+/// An ERROR sent by the client in reply to our OACK means it refused the options
+/// (RFC 2347). The session just ends there; errors must never be answered.
+fn option_refusal(conn: &TftpConnection, e: TftpError) -> RequestError {
+	if e.is_option_refusal() {
+		info!("{} refused the negotiated options: {}", conn.peer(), e.msg());
+		OptionError::Refused.into()
+	} else {
+		ConnectionError::PeerError(e).into()
+	}
+}
+
+// ############################################################################
+// #### STORAGE ###############################################################
+// ############################################################################
+
+///
+/// Where the server reads requested files from and writes uploaded files to.
+///
+/// Errors of kind `NotFound` and `PermissionDenied` are reported to the client as
+/// such, anything else as a storage error.
+///
+pub trait TftpStorage: Send + Sync {
+	/// Opens a file for an RRQ. The size is sent to clients asking for tsize, if known.
+	fn open_read(&self, filename: &str) -> io::Result<(Box<dyn AsyncRead + Unpin + Send>, Option<u64>)>;
+	/// Opens a file for a WRQ.
+	fn open_write(&self, filename: &str) -> io::Result<Box<dyn AsyncWrite + Unpin + Send>>;
+	/// The file on the local file system, if there is one. Only such files can be
+	/// sent via multicast.
+	fn local_path(&self, _filename: &str) -> Option<PathBuf> {
+		None
+	}
+}
+
+/// Files in a directory on the local file system. Names that would lead out of it,
+/// i.e. absolute ones and those containing `..`, are refused.
+#[derive(Debug, Clone)]
+pub struct FsStorage {
+	root: PathBuf,
+}
+impl FsStorage {
+	pub fn new(root: PathBuf) -> Self {
+		Self { root }
+	}
+
+	fn path(&self, filename: &str) -> io::Result<PathBuf> {
+		let path = Path::new(filename);
+		if !path.components().all(|e| matches!(e, Component::Normal(_) | Component::CurDir)) {
+			return Err(io::Error::new(io::ErrorKind::PermissionDenied, format!("'{}' is outside of the root directory", filename)));
+		}
+		Ok(self.root.join(path))
+	}
+}
+impl TftpStorage for FsStorage {
+	fn open_read(&self, filename: &str) -> io::Result<(Box<dyn AsyncRead + Unpin + Send>, Option<u64>)> {
+		let file = File::open(self.path(filename)?)?;
+		let size = file.metadata()?.len();
+		Ok((Box::new(tokio::fs::File::from_std(file)), Some(size)))
+	}
+
+	fn open_write(&self, filename: &str) -> io::Result<Box<dyn AsyncWrite + Unpin + Send>> {
+		let file = OpenOptions::new().create(true).truncate(true).write(true).open(self.path(filename)?)?;
+		Ok(Box::new(tokio::fs::File::from_std(file)))
+	}
+
+	fn local_path(&self, filename: &str) -> Option<PathBuf> {
+		self.path(filename).ok()
+	}
+}
+
+enum LocalFile {
+	Reader(Box<dyn AsyncRead + Unpin + Send>),
+	Writer(Box<dyn AsyncWrite + Unpin + Send>),
+}
+
 pub struct TftpServer {
 	listen_addr: SocketAddr,
 	socket: UdpSocket,
-	root: PathBuf,
+	storage: Arc<dyn TftpStorage>,
+	dally: Option<Duration>,
+	policy: NegotiationPolicy,
+	multicast: Option<MulticastSessions>,
 }
 impl TftpServer {
 
-	pub fn new(listen_addr: SocketAddr, root: PathBuf) -> std::result::Result<Self, Box<dyn Error>> {
+	pub fn new(listen_addr: SocketAddr, root: PathBuf) -> io::Result<Self> {
 		let socket = UdpSocket::bind(listen_addr)?;
-		socket.set_read_timeout(Some(Duration::from_millis(500)))?;
+		/* Driven by Tokio once the server runs */
+		socket.set_nonblocking(true)?;
+
+		Ok(Self {
+			listen_addr,
+			socket,
+			storage: Arc::new(FsStorage::new(root)),
+			dally: None,
+			policy: NegotiationPolicy::default(),
+			multicast: None
+		})
+	}
+
+	/// The address requests are received on, with the port chosen by the system if 0 was given.
+	pub fn local_addr(&self) -> io::Result<SocketAddr> {
+		self.socket.local_addr()
+	}
 
-		Ok(Self { listen_addr, socket, root })
+	pub fn set_dally_period(&mut self, dally: Option<Duration>) {
+		self.dally = dally
+	}
+	pub fn set_negotiation_policy(&mut self, policy: NegotiationPolicy) {
+		self.policy = policy
+	}
+	/// Serve files from somewhere else than the root directory given to `new`.
+	pub fn set_storage(&mut self, storage: Arc<dyn TftpStorage>) {
+		self.storage = storage
+	}
+	/// Serve clients asking for the multicast option (RFC 2090) via multicast.
+	pub fn set_multicast(&mut self, config: Option<MulticastConfig>) {
+		self.multicast = config.map(MulticastSessions::new)
 	}
 
 	pub async fn run(&self, cxl_token: CancellationToken) -> Result<()> {
+		/* Clients whose request is being handled. A client retransmits its request from the
+		 * same port if we don't answer in time, which must not start a second session */
+		let active: Arc<Mutex<HashSet<SocketAddr>>> = Arc::new(Mutex::new(HashSet::new()));
+		let socket = tokio::net::UdpSocket::from_std(self.socket.try_clone()?)?;
 		loop {
-			if cxl_token.is_cancelled() {
-				warn!("Server task cancelled by signal");
-				break;
-			}
-
 			/* this buffer will be moved into the task below */
-			let mut recv_buf = Box::new([0; 128]);
-			match self.socket.recv_from(recv_buf.as_mut()) {
+			let mut recv_buf = Box::new([0; 512]);
+			let recv = tokio::select! {
+				_ = cxl_token.cancelled() => {
+					warn!("Server task cancelled by signal");
+					break;
+				},
+				recv = socket.recv_from(recv_buf.as_mut()) => recv,
+			};
+			match recv {
 				Ok((size, client)) => {
 					debug!("received packet ({} bytes) from {}", size, client);
+					let Some(session) = ActiveSession::start(&active, client) else {
+						debug!("ignoring request from {}, its session is still running", client);
+						continue;
+					};
 	
 					let task_cxl_token = cxl_token.clone();
 					let listen_addr = self.listen_addr.ip();
-					let root_dir = self.root.clone();
+					let storage = self.storage.clone();
+					let dally = self.dally;
+					let policy = self.policy.clone();
+					let multicast = self.multicast.clone();
 					tokio::spawn(async move {
+						let _session = session;
 						let Ok(packet) = pkt::TftpReq::try_from(&recv_buf[..size]) else {
 							return error!("only TFTP requests accepted on this socket (client: {})", client);
 						};
-						TftpRequestHandler
-							::new(listen_addr, root_dir, task_cxl_token)
+						let mut handler = TftpRequestHandler::new(listen_addr, storage, task_cxl_token);
+						handler.set_dally_period(dally);
+						handler.set_negotiation_policy(policy);
+						handler.set_multicast_sessions(multicast);
+						handler
 							.handle_request(packet, client)
 							.await
 							.ok();
 					});
 				},
-				Err(e) => {
-					match e.kind() {
-						std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock => (),
-						_ => error!("{}", e)
+				Err(e) => error!("{}", e),
+			}
+		}
+		Ok(())
+	}
+}
+
+/// Marks a client as being served until dropped.
+struct ActiveSession {
+	clients: Arc<Mutex<HashSet<SocketAddr>>>,
+	client: SocketAddr,
+}
+impl ActiveSession {
+	/// None if the client is already being served.
+	fn start(clients: &Arc<Mutex<HashSet<SocketAddr>>>, client: SocketAddr) -> Option<Self> {
+		match clients.lock().unwrap().insert(client) {
+			true => Some(Self { clients: clients.clone(), client }),
+			false => None,
+		}
+	}
+}
+impl Drop for ActiveSession {
+	fn drop(&mut self) {
+		self.clients.lock().unwrap().remove(&self.client);
+	}
+}
+
+// ############################################################################
+// #### MULTICAST (RFC 2090) ##################################################
+// ############################################################################
+
+/// Where and how files are sent via multicast.
+#[derive(Debug, Clone, Copy)]
+pub struct MulticastConfig {
+	/// Group of the first session; the addresses of further sessions count up from it.
+	pub group: SocketAddrV4,
+	/// Upper bound on concurrent sessions, i.e. groups in use.
+	pub max_sessions: u8,
+	pub ttl: u32,
+}
+impl MulticastConfig {
+	pub fn new(group: SocketAddrV4) -> Self {
+		Self { group, max_sessions: 16, ttl: 1 }
+	}
+}
+
+struct MulticastClient {
+	addr: SocketAddr,
+	/// The options acknowledged to this client, besides the multicast option.
+	options: Vec<TftpOption>,
+}
+
+struct SessionHandle {
+	group: SocketAddrV4,
+	blocksize: u16,
+	joins: mpsc::Sender<MulticastClient>,
+}
+
+/// The running multicast sessions, one per file.
+#[derive(Clone)]
+struct MulticastSessions {
+	config: MulticastConfig,
+	sessions: Arc<Mutex<HashMap<PathBuf, SessionHandle>>>,
+}
+impl MulticastSessions {
+	pub fn new(config: MulticastConfig) -> Self {
+		Self { config, sessions: Arc::new(Mutex::new(HashMap::new())) }
+	}
+
+	/// Adds the client to the session sending `path`, starting one if there is none.
+	/// A client joining a running session must accept its blksize, so its own is
+	/// lowered if needed; clients asking for less are served via unicast.
+	fn join(
+		&self,
+		path: &Path,
+		addr: SocketAddr,
+		mut options: Vec<TftpOption>,
+		local: Ipv4Addr,
+		cancel_token: &CancellationToken
+	) -> Result<bool> {
+		let requested_blocksize = options.iter().find_map(|e| match e {
+			TftpOption::Blocksize(bs) => Some(*bs),
+			_ => None,
+		});
+		let mut sessions = self.sessions.lock().unwrap();
+
+		if let Some(session) = sessions.get(path) {
+			match requested_blocksize {
+				Some(bs) if bs >= session.blocksize => {
+					options.iter_mut()
+						.filter(|e| e.kind() == TftpOptionKind::Blocksize)
+						.for_each(|e| *e = TftpOption::Blocksize(session.blocksize));
+				},
+				None if session.blocksize == consts::DEFAULT_BLOCK_SIZE => (),
+				_ => return Ok(false),
+			}
+			debug!("{} joins multicast session {} for '{}'", addr, session.group, path.display());
+			return Ok(session.joins.send(MulticastClient { addr, options }).is_ok());
+		}
+
+		let in_use: Vec<SocketAddrV4> = sessions.values().map(|e| e.group).collect();
+		let Some(group) = (0..self.config.max_sessions as u32)
+			.map(|i| SocketAddrV4::new(
+				Ipv4Addr::from(u32::from(*self.config.group.ip()).wrapping_add(i)),
+				self.config.group.port()
+			))
+			.find(|e| !in_use.contains(e)) else {
+			warn!("no free multicast group for '{}'", path.display());
+			return Ok(false);
+		};
+
+		let blocksize = requested_blocksize.unwrap_or(consts::DEFAULT_BLOCK_SIZE);
+		let file = File::open(path)?;
+		let blocks = file.metadata()?.len() / blocksize as u64 + 1;
+		if blocks > u16::MAX as u64 {
+			/* Block numbers would wrap, which multicast clients can't tell apart */
+			return Ok(false);
+		}
+
+		let socket = multicast::sender_socket(local, self.config.ttl)?;
+		socket.set_read_timeout(Some(Duration::from_millis(100)))?;
+		/* utimeout comes after timeout and takes precedence */
+		let timeout = options.iter().rev().find_map(|e| match e {
+			TftpOption::Timeout(t) | TftpOption::UTimeout(t) => Some(*t),
+			_ => None,
+		}).unwrap_or(Duration::from_secs(consts::DEFAULT_TIMEOUT_SECS as u64));
+
+		let (tx, rx) = mpsc::channel();
+		tx.send(MulticastClient { addr, options }).ok();
+		sessions.insert(path.to_path_buf(), SessionHandle { group, blocksize, joins: tx });
+		info!("starting multicast session {} for '{}'", group, path.display());
+
+		let session = MulticastSession {
+			socket,
+			group,
+			file,
+			blocksize,
+			last_block: blocks as u16,
+			timeout,
+			clients: VecDeque::new(),
+			joins: rx,
+			path: path.to_path_buf(),
+			sessions: self.sessions.clone(),
+			cancel_token: cancel_token.clone(),
+			served: 0,
+			blocks_sent: 0,
+		};
+		/* Sessions run as long as clients keep joining, so they get their own thread */
+		tokio::task::spawn_blocking(move || session.run());
+		Ok(true)
+	}
+}
+
+/// A packet waiting to be acknowledged by the master client.
+struct Pending {
+	packet: Vec<u8>,
+	to: SocketAddr,
+	/// Block number for DATA, `None` for the OACK making a client master.
+	blocknum: Option<u16>,
+	sent: Instant,
+	attempts: u8,
+}
+
+///
+/// Sends one file to a multicast group. The first client in the queue is the
+/// master client: it ACKs the blocks and thereby tells which block to send next,
+/// while all the others only listen. Once the master has everything, the next
+/// client becomes master and asks for the blocks it missed.
+///
+struct MulticastSession {
+	socket: UdpSocket,
+	group: SocketAddrV4,
+	file: File,
+	blocksize: u16,
+	last_block: u16,
+	timeout: Duration,
+	clients: VecDeque<MulticastClient>,
+	joins: mpsc::Receiver<MulticastClient>,
+	path: PathBuf,
+	sessions: Arc<Mutex<HashMap<PathBuf, SessionHandle>>>,
+	cancel_token: CancellationToken,
+	/// Clients which received the whole file.
+	served: u32,
+	blocks_sent: u64,
+}
+impl MulticastSession {
+
+	fn run(mut self) {
+		match self.serve() {
+			Ok(()) => info!(
+				"multicast session {} for '{}' ended: {} clients served, {} blocks sent",
+				self.group, self.path.display(), self.served, self.blocks_sent
+			),
+			Err(e) => {
+				error!("multicast session {} failed: {}", self.group, e);
+				self.sessions.lock().unwrap().remove(&self.path);
+			},
+		}
+	}
+
+	/// Returns once the session is deregistered, i.e. no client can join anymore.
+	fn serve(&mut self) -> io::Result<()> {
+		let mut buf = [0u8; 512];
+		let mut master: Option<SocketAddr> = None;
+		let mut pending: Option<Pending> = None;
+
+		loop {
+			if self.cancel_token.is_cancelled() {
+				self.sessions.lock().unwrap().remove(&self.path);
+				return Ok(());
+			}
+
+			while let Ok(client) = self.joins.try_recv() {
+				if master.is_some() {
+					self.send_oack(&client, false)?;
+				}
+				self.clients.push_back(client);
+			}
+
+			if master.is_none() {
+				if self.clients.is_empty() {
+					/* Deregister while no new client can be handed to us */
+					let mut sessions = self.sessions.lock().unwrap();
+					match self.joins.try_recv() {
+						Ok(client) => self.clients.push_back(client),
+						Err(_) => {
+							sessions.remove(&self.path);
+							return Ok(());
+						},
+					}
+				}
+				let client = &self.clients[0];
+				master = Some(client.addr);
+				pending = Some(self.send_oack(client, true)?);
+			}
+
+			match self.socket.recv_from(&mut buf) {
+				Ok((len, from)) => match pkt::TftpPacket::try_from_buf(&buf[..len]) {
+					Ok(pkt::TftpPacket::Ack(ack)) if ack.blocknum() >= self.last_block => {
+						if self.clients.iter().any(|e| e.addr == from) {
+							debug!("{} received '{}' via multicast", from, self.path.display());
+							self.served += 1;
+						}
+						self.clients.retain(|e| e.addr != from);
+						if master == Some(from) {
+							master = None;
+							pending = None;
+						}
+					},
+					Ok(pkt::TftpPacket::Ack(ack)) if master == Some(from) => {
+						let next = ack.blocknum() + 1;
+						/* A duplicate ACK must not trigger another DATA (RFC 1123) */
+						if pending.as_ref().and_then(|e| e.blocknum) != Some(next) {
+							pending = Some(self.send_block(next)?);
+						}
+					},
+					Ok(pkt::TftpPacket::Err(e)) => {
+						info!("{} left multicast session: {}", from, e.error_msg());
+						self.clients.retain(|e| e.addr != from);
+						if master == Some(from) {
+							master = None;
+							pending = None;
+						}
+					},
+					_ => (),
+				},
+				Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => (),
+				Err(e) => return Err(e),
+			}
+
+			if let Some(p) = pending.as_mut() {
+				if p.sent.elapsed() >= self.timeout {
+					if p.attempts >= consts::DEFAULT_RETRANSMIT_ATTEMPTS {
+						warn!("master client {} of multicast session {} not responding", p.to, self.group);
+						self.clients.retain(|e| Some(e.addr) != master);
+						master = None;
+						pending = None;
+					} else {
+						self.socket.send_to(&p.packet, p.to)?;
+						p.attempts += 1;
+						p.sent = Instant::now();
 					}
 				}
 			}
 		}
-		Ok(())
 	}
-}
\ No newline at end of file
+
+	fn send_oack(&self, client: &MulticastClient, master: bool) -> io::Result<Pending> {
+		let options = multicast::oack_options(&client.options, self.group, master);
+		let oack = pkt::builder::TftpOAckBuilder::new().options(&options[..]).build();
+		self.socket.send_to(oack.as_bytes(), client.addr)?;
+
+		Ok(Pending {
+			packet: oack.as_bytes().to_vec(),
+			to: client.addr,
+			blocknum: None,
+			sent: Instant::now(),
+			attempts: 0,
+		})
+	}
+
+	fn send_block(&mut self, blocknum: u16) -> io::Result<Pending> {
+		let mut packet: Vec<u8> = Vec::with_capacity(4 + self.blocksize as usize);
+		packet.extend([0; 4]);
+		self.file.seek(SeekFrom::Start((blocknum as u64 - 1) * self.blocksize as u64))?;
+		self.file.by_ref().take(self.blocksize as u64).read_to_end(&mut packet)?;
+		pkt::MutableTftpData::from(&mut packet[..]).set_blocknum(blocknum);
+
+		let to = SocketAddr::V4(self.group);
+		self.socket.send_to(&packet, to)?;
+		self.blocks_sent += 1;
+		Ok(Pending { packet, to, blocknum: Some(blocknum), sent: Instant::now(), attempts: 0 })
+	}
+}
+
+// ############################################################################
+// #### TESTS #################################################################
+// ############################################################################
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn fs_storage_stays_in_its_root() {
+		let storage = FsStorage::new(PathBuf::from("/srv/tftp"));
+
+		assert_eq!(storage.path("pxelinux.0").unwrap(), PathBuf::from("/srv/tftp/pxelinux.0"));
+		assert_eq!(storage.path("./boot/default").unwrap(), PathBuf::from("/srv/tftp/./boot/default"));
+		for name in ["/etc/hostname", "../etc/hostname", "boot/../../etc/hostname", "boot/.."] {
+			let err = storage.path(name).unwrap_err();
+			assert_eq!(err.kind(), io::ErrorKind::PermissionDenied, "{}", name);
+			assert!(storage.local_path(name).is_none(), "{}", name);
+		}
+	}
+}
@@ -0,0 +1,335 @@
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+#[allow(unused)]
+use log::{info, warn, error, debug, trace};
+use rustyline::error::ReadlineError;
+use rustyline::{Config, DefaultEditor};
+use tokio_util::sync::CancellationToken;
+
+use tftp::client::{TftpClient, TftpRequestParameters};
+use tftp::options::{TftpOption, TftpOptionKind};
+use tftp::{Mode, RequestKind};
+
+use crate::cli;
+use crate::progress::ProgressReporter;
+use crate::target::{self, ServerTarget};
+
+const PROMPT: &str = "tftp> ";
+const HISTORY_FILE: &str = "~/.tftp_history";
+/// Lines kept in the history file.
+const HISTORY_LEN: usize = 500;
+
+const HELP: &str = "\
+connect HOST [PORT]    set the server for following transfers
+get REMOTE [LOCAL]     download a file
+put LOCAL [REMOTE]     upload a file
+mode [octet|netascii]  show or set the transfer mode; 'binary' and 'ascii' work as well
+blksize [N|off]        show or set the blksize to request
+timeout [SECS|off]     show or set the timeout, fractions are requested via utimeout
+tsize                  toggle requesting/sending the transfer size
+windowsize [N|off]     show or set the windowsize (RFC 7440) to request
+status                 show the current settings
+verbose                toggle printing transfer statistics and more messages
+trace                  toggle printing every packet sent and received
+quit                   leave, as does Ctrl-D";
+
+///
+/// State of the interactive client. Each transfer is a separate request on the same
+/// `TftpClient`, with the options as currently set.
+///
+struct Shell {
+	client: TftpClient,
+	root: PathBuf,
+	/// SIGINT cancels the token in here, which is replaced for the next transfer.
+	interrupt: Arc<Mutex<CancellationToken>>,
+	server: Option<ServerTarget>,
+	port: u16,
+	mode: Mode,
+	options: Vec<TftpOption>,
+	timeout: Option<Duration>,
+	verbose: bool,
+	trace: bool,
+	/// Log level given on the command line, restored when verbose and trace are off.
+	log_level: log::LevelFilter,
+}
+
+pub async fn run(
+	opts: cli::ClientOpts,
+	server: Option<ServerTarget>,
+	port: u16,
+	root: PathBuf,
+	interrupt: Arc<Mutex<CancellationToken>>,
+) -> std::io::Result<()> {
+	let mut client = TftpClient::new(interrupt.lock().unwrap().clone());
+	client.set_dally_period(opts.dally.map(|d| Duration::from_secs(d as u64)));
+	client.set_auto_blocksize(opts.blocksize == cli::Blocksize::Auto);
+	client.set_blocksize_probing(opts.probe_blocksize);
+	client.set_retry_policy(cli::retry_policy(&opts, 0));
+	let timeout = (opts.timeout != Duration::from_secs(tftp::consts::DEFAULT_TIMEOUT_SECS as u64)).then_some(opts.timeout);
+
+	let mut shell = Shell {
+		client,
+		root,
+		interrupt,
+		server,
+		port,
+		mode: Mode::Octet,
+		options: cli::parse_tftp_options(opts),
+		timeout,
+		verbose: false,
+		trace: false,
+		log_level: log::max_level(),
+	};
+
+	let history = PathBuf::from(shellexpand::tilde(HISTORY_FILE).as_ref());
+	let config = Config::builder()
+		.max_history_size(HISTORY_LEN)
+		.and_then(|builder| builder.history_ignore_dups(true))
+		.map_err(io::Error::other)?
+		.auto_add_history(true)
+		.build();
+	let mut editor = DefaultEditor::with_config(config).map_err(io::Error::other)?;
+	editor.load_history(&history).ok();
+	loop {
+		/* Waiting for the user blocks, which must not happen on one of the runtime's workers */
+		let (returned, line) = tokio::task::spawn_blocking(move || {
+			let line = editor.readline(PROMPT);
+			(editor, line)
+		}).await.map_err(io::Error::other)?;
+		editor = returned;
+		let line = match line {
+			Ok(line) => line,
+			Err(ReadlineError::Interrupted) => continue,
+			Err(ReadlineError::Eof) => break,
+			Err(e) => return Err(io::Error::other(e)),
+		};
+		let args: Vec<&str> = line.split_whitespace().collect();
+		let Some((cmd, args)) = args.split_first() else {
+			continue;
+		};
+		match shell.execute(cmd, args).await {
+			Ok(true) => (),
+			Ok(false) => break,
+			Err(e) => println!("{}", e),
+		}
+	}
+
+	editor.save_history(&history).ok();
+	Ok(())
+}
+
+impl Shell {
+	/// Runs a command, returns false when the shell shall be left.
+	async fn execute(&mut self, cmd: &str, args: &[&str]) -> Result<bool, String> {
+		match (cmd, args) {
+			("quit" | "exit" | "q", []) => return Ok(false),
+			("help" | "?", []) => println!("{}", HELP),
+			("connect", [host]) => self.connect(host, None)?,
+			("connect", [host, port]) => self.connect(host, Some(port))?,
+			("get", [remote]) => self.transfer(RequestKind::Rrq, remote, None).await?,
+			("get", [remote, local]) => self.transfer(RequestKind::Rrq, remote, Some(local)).await?,
+			("put", [local]) => self.transfer(RequestKind::Wrq, local, None).await?,
+			("put", [local, remote]) => self.transfer(RequestKind::Wrq, local, Some(remote)).await?,
+			("mode", []) => println!("mode {}", self.mode),
+			("mode", [mode]) => self.mode = match *mode {
+				"binary" => Mode::Octet,
+				"ascii" => Mode::NetAscii,
+				mode => mode.parse::<Mode>().map_err(|_| format!("unknown mode '{}'", mode))?,
+			},
+			("binary", []) => self.mode = Mode::Octet,
+			("ascii", []) => self.mode = Mode::NetAscii,
+			("blksize", []) => match self.option(TftpOptionKind::Blocksize) {
+				Some(TftpOption::Blocksize(bs)) => println!("blksize {}", bs),
+				_ => println!("blksize not requested"),
+			},
+			("blksize", ["off"]) => self.remove_option(TftpOptionKind::Blocksize),
+			("blksize", [bs]) => match bs.parse::<cli::Blocksize>()? {
+				cli::Blocksize::Fixed(bs) => self.set_option(TftpOption::Blocksize(bs)),
+				cli::Blocksize::Auto => return Err("use --blocksize auto on the command line instead".to_string()),
+			},
+			("timeout", []) => match self.timeout {
+				Some(timeout) => println!("timeout {:.3}s", timeout.as_secs_f64()),
+				None => println!("timeout not requested, {}s by default", tftp::consts::DEFAULT_TIMEOUT_SECS),
+			},
+			("timeout", ["off"]) => {
+				self.timeout = None;
+				self.remove_option(TftpOptionKind::Timeout);
+				self.remove_option(TftpOptionKind::UTimeout);
+			},
+			("timeout", [secs]) => self.set_timeout(cli::parse_timeout(secs)?),
+			("tsize", []) => match self.option(TftpOptionKind::TransferSize) {
+				Some(_) => {
+					self.remove_option(TftpOptionKind::TransferSize);
+					println!("tsize off");
+				},
+				None => {
+					self.set_option(TftpOption::TransferSize(0));
+					println!("tsize on");
+				},
+			},
+			("windowsize", []) => match self.option(TftpOptionKind::WindowSize) {
+				Some(TftpOption::WindowSize(ws)) => println!("windowsize {}", ws),
+				_ => println!("windowsize not requested"),
+			},
+			("windowsize", ["off"]) => self.remove_option(TftpOptionKind::WindowSize),
+			("windowsize", [ws]) => match ws.parse::<u16>() {
+				Ok(ws) if ws >= 1 => self.set_option(TftpOption::WindowSize(ws)),
+				_ => return Err(format!("invalid windowsize '{}', must be 1 to 65535", ws)),
+			},
+			("status", []) => self.status(),
+			("verbose", []) => {
+				self.verbose = !self.verbose;
+				self.apply_log_level();
+				println!("verbose {}", on_off(self.verbose));
+			},
+			("trace", []) => {
+				self.trace = !self.trace;
+				self.apply_log_level();
+				println!("packet tracing {}", on_off(self.trace));
+			},
+			(cmd, _) if HELP.lines().any(|l| l.split_whitespace().next() == Some(cmd)) || ["binary", "ascii"].contains(&cmd) => {
+				return Err(format!("wrong arguments for '{}', see 'help'", cmd));
+			},
+			(cmd, _) => return Err(format!("unknown command '{}', see 'help'", cmd)),
+		}
+		Ok(true)
+	}
+
+	fn connect(&mut self, host: &str, port: Option<&str>) -> Result<(), String> {
+		let mut target = host.parse::<ServerTarget>()?;
+		if let Some(port) = port {
+			target.port = Some(port.parse::<u16>().map_err(|_| format!("invalid port '{}'", port))?);
+		}
+		if target.file.is_some() {
+			return Err("give the server only, files go with get and put".to_string());
+		}
+		self.server = Some(target);
+		Ok(())
+	}
+
+	async fn transfer(&mut self, kind: RequestKind, file: &str, other: Option<&&str>) -> Result<(), String> {
+		let (local, remote) = match kind {
+			RequestKind::Rrq => match other {
+				Some(local) => (PathBuf::from(local), file.to_string()),
+				None => (cli::default_local(file)?, file.to_string()),
+			},
+			RequestKind::Wrq => match other {
+				Some(remote) => (PathBuf::from(file), remote.to_string()),
+				None => (PathBuf::from(file), cli::default_remote(&PathBuf::from(file))?),
+			},
+		};
+		let Some(server) = self.server.as_ref() else {
+			return Err("not connected, use 'connect HOST' first".to_string());
+		};
+		let servers = server.resolve(self.port).await.map_err(|e| e.to_string())?;
+
+		/* A previous transfer may have been interrupted, which cancelled the token */
+		let token = {
+			let mut token = self.interrupt.lock().unwrap();
+			if token.is_cancelled() {
+				*token = CancellationToken::new();
+			}
+			token.clone()
+		};
+		self.client.set_cancellation_token(token);
+
+		let reporter = ProgressReporter::new(None, false).map(Arc::new);
+		let res = target::try_each(servers, |server| {
+			let mut params = TftpRequestParameters::new(kind, server, self.root.join(&local))
+				.remote(&remote)
+				.options(&self.options)
+				.mode(self.mode);
+			if let Some(timeout) = self.timeout {
+				params = params.timeout(timeout);
+			}
+			if let Some(reporter) = reporter.as_ref() {
+				params = params.progress(reporter.clone());
+			}
+			let client = &self.client;
+			async move { client.request(&params).await }
+		}).await;
+		if let Some(reporter) = reporter.as_ref() {
+			reporter.finish();
+		}
+		let stats = res.map_err(|e| format!("transfer failed: {}", e))?;
+		match kind {
+			RequestKind::Rrq => println!("Received {} bytes in {:.1} seconds", stats.bytes, stats.duration.as_secs_f64()),
+			RequestKind::Wrq => println!("Sent {} bytes in {:.1} seconds", stats.bytes, stats.duration.as_secs_f64()),
+		}
+		if self.verbose {
+			println!("{}", stats);
+		}
+		Ok(())
+	}
+
+	fn status(&self) {
+		match self.server.as_ref() {
+			Some(server) if server.port.is_none() => println!("Connected to {} port {}.", server, self.port),
+			Some(server) => println!("Connected to {}.", server),
+			None => println!("Not connected."),
+		}
+		println!("Mode: {}  Verbose: {}  Tracing: {}", self.mode, on_off(self.verbose), on_off(self.trace));
+		let blksize = match self.option(TftpOptionKind::Blocksize) {
+			Some(TftpOption::Blocksize(bs)) => bs.to_string(),
+			_ => "default".to_string(),
+		};
+		let timeout = match self.timeout {
+			Some(timeout) => format!("{:.3}s", timeout.as_secs_f64()),
+			None => "default".to_string(),
+		};
+		let tsize = self.option(TftpOptionKind::TransferSize).is_some();
+		let windowsize = match self.option(TftpOptionKind::WindowSize) {
+			Some(TftpOption::WindowSize(ws)) => ws.to_string(),
+			_ => "default".to_string(),
+		};
+		println!("Blksize: {}  Timeout: {}  Tsize: {}  Windowsize: {}", blksize, timeout, on_off(tsize), windowsize);
+		let others: Vec<String> = self.options
+			.iter()
+			.filter(|e| matches!(e.kind(), TftpOptionKind::Multicast | TftpOptionKind::Custom))
+			.map(|e| match e.as_str_tuple() {
+				(name, value) if value.is_empty() => name.to_string(),
+				(name, value) => format!("{}={}", name, value),
+			})
+			.collect();
+		if !others.is_empty() {
+			println!("Other options: {}", others.join(", "));
+		}
+	}
+
+	fn option(&self, kind: TftpOptionKind) -> Option<&TftpOption> {
+		self.options.iter().find(|e| e.kind() == kind)
+	}
+	fn set_option(&mut self, option: TftpOption) {
+		self.remove_option(option.kind());
+		self.options.push(option);
+	}
+	fn remove_option(&mut self, kind: TftpOptionKind) {
+		self.options.retain(|e| e.kind() != kind);
+	}
+
+	fn set_timeout(&mut self, timeout: Duration) {
+		self.timeout = Some(timeout);
+		for option in cli::timeout_options(timeout) {
+			self.set_option(option);
+		}
+		if timeout.subsec_nanos() == 0 {
+			self.remove_option(TftpOptionKind::UTimeout);
+		}
+	}
+
+	fn apply_log_level(&self) {
+		let level = match (self.trace, self.verbose) {
+			(true, _) => log::LevelFilter::Trace,
+			(false, true) => self.log_level.max(log::LevelFilter::Info),
+			(false, false) => self.log_level,
+		};
+		log::set_max_level(level);
+	}
+}
+
+fn on_off(on: bool) -> &'static str {
+	if on { "on" } else { "off" }
+}
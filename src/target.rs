@@ -0,0 +1,217 @@
+use std::fmt::Display;
+#[cfg(feature = "client")]
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV6};
+use std::str::FromStr;
+
+#[cfg(feature = "client")]
+use tftp::error::RequestError;
+use tftp::Mode;
+
+const URI_SCHEME: &str = "tftp://";
+
+///
+/// The server given on the command line: a host name or address with optional port,
+/// or a `tftp://host[:port]/file[;mode=octet]` URI (RFC 3617).
+///
+/// IPv6 addresses may carry a zone, e.g. `fe80::1%eth0` or `[fe80::1%eth0]:69`; in
+/// URIs the `%` is escaped as `%25` (RFC 6874).
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerTarget {
+	pub host: String,
+	/// Interface name or index of a link-local IPv6 address.
+	pub zone: Option<String>,
+	pub port: Option<u16>,
+	/// The file named by a URI.
+	pub file: Option<String>,
+	pub mode: Option<Mode>,
+}
+
+impl ServerTarget {
+	///
+	/// Returns all addresses of the server, to be tried in order. Host names are
+	/// looked up, which yields both A and AAAA records.
+	///
+	pub async fn resolve(&self, default_port: u16) -> io::Result<Vec<SocketAddr>> {
+		let port = self.port.unwrap_or(default_port);
+
+		if let Ok(ip) = self.host.parse::<IpAddr>() {
+			return Ok(vec![match (ip, self.zone.as_ref()) {
+				(IpAddr::V6(ip), Some(zone)) => SocketAddr::V6(SocketAddrV6::new(ip, port, 0, zone_index(zone)?)),
+				(ip, None) => SocketAddr::new(ip, port),
+				(IpAddr::V4(_), Some(_)) => return Err(invalid("zones are only supported with IPv6 addresses")),
+			}]);
+		}
+		if self.zone.is_some() {
+			return Err(invalid("zones are only supported with IPv6 addresses"));
+		}
+
+		let addrs: Vec<SocketAddr> = tokio::net::lookup_host((self.host.as_str(), port)).await?.collect();
+		if addrs.is_empty() {
+			return Err(io::Error::new(io::ErrorKind::NotFound, format!("'{}' has no addresses", self.host)));
+		}
+		Ok(addrs)
+	}
+}
+
+///
+/// Runs `request` against each of `addrs` in turn, until one of them answers. Host
+/// names may resolve to several addresses, of which only some may be reachable.
+/// Only failures before the first reply move on to the next address; once data was
+/// read from or written to stdin or stdout another try would truncate or repeat it.
+///
+#[cfg(feature = "client")]
+pub async fn try_each<T, F, Fut>(addrs: Vec<SocketAddr>, mut request: F) -> tftp::client::Result<T>
+where
+	F: FnMut(SocketAddr) -> Fut,
+	Fut: Future<Output = tftp::client::Result<T>>,
+{
+	let mut addrs = addrs.into_iter().peekable();
+	while let Some(addr) = addrs.next() {
+		match request(addr).await {
+			Err(RequestError::NoResponse | RequestError::Unreachable(_)) if addrs.peek().is_some() => {
+				log::warn!("no response from {}, trying the next address", addr);
+			},
+			res => return res,
+		}
+	}
+	Err(RequestError::NoResponse)
+}
+
+impl Display for ServerTarget {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self.zone.as_ref() {
+			Some(zone) => write!(f, "{}%{}", self.host, zone)?,
+			None => write!(f, "{}", self.host)?,
+		}
+		if let Some(port) = self.port {
+			write!(f, " port {}", port)?;
+		}
+		Ok(())
+	}
+}
+
+impl FromStr for ServerTarget {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let is_uri = s.len() >= URI_SCHEME.len() && s[..URI_SCHEME.len()].eq_ignore_ascii_case(URI_SCHEME);
+		if !is_uri {
+			let (host, zone, port) = parse_authority(s, false)?;
+			return Ok(Self { host, zone, port, file: None, mode: None });
+		}
+
+		let rest = &s[URI_SCHEME.len()..];
+		let Some((authority, path)) = rest.split_once('/') else {
+			return Err("the URI doesn't name a file".to_string());
+		};
+		let (host, zone, port) = parse_authority(authority, true)?;
+
+		let (file, mode) = match path.rsplit_once(';') {
+			Some((file, param)) => {
+				let Some((key, value)) = param.split_once('=').filter(|(k, _)| k.eq_ignore_ascii_case("mode")) else {
+					return Err(format!("unknown URI parameter '{}'", param));
+				};
+				(file, Some(value.parse::<Mode>().map_err(|_| format!("unsupported mode '{}={}'", key, value))?))
+			},
+			None => (path, None),
+		};
+		let file = percent_decode(file)?;
+		if file.is_empty() {
+			return Err("the URI doesn't name a file".to_string());
+		}
+
+		Ok(Self { host, zone, port, file: Some(file), mode })
+	}
+}
+
+/// Splits `host`, `host:port`, `[v6addr%zone]:port` or a bare IPv6 address.
+fn parse_authority(s: &str, uri: bool) -> Result<(String, Option<String>, Option<u16>), String> {
+	let parse_port = |p: &str| p.parse::<u16>().map_err(|_| format!("invalid port '{}'", p));
+
+	let (host, port) = if let Some(bracketed) = s.strip_prefix('[') {
+		let Some((host, rest)) = bracketed.split_once(']') else {
+			return Err(format!("missing ']' in '{}'", s));
+		};
+		match rest {
+			"" => (host, None),
+			_ => match rest.strip_prefix(':') {
+				Some(port) => (host, Some(parse_port(port)?)),
+				None => return Err(format!("unexpected '{}' after the address", rest)),
+			},
+		}
+	} else {
+		match s.split_once(':') {
+			/* More than one colon: a bare IPv6 address */
+			Some((host, port)) if !port.contains(':') => (host, Some(parse_port(port)?)),
+			_ => (s, None),
+		}
+	};
+
+	let (host, zone) = match host.split_once('%') {
+		Some((addr, zone)) => {
+			/* RFC 6874 escapes the '%' in URIs */
+			let zone = match uri {
+				true => zone.strip_prefix("25").unwrap_or(zone),
+				false => zone,
+			};
+			if addr.parse::<Ipv6Addr>().is_err() || zone.is_empty() {
+				return Err(format!("zones are only supported with IPv6 addresses, got '{}'", host));
+			}
+			(addr, Some(zone.to_string()))
+		},
+		None => (host, None),
+	};
+	if host.is_empty() {
+		return Err("missing host".to_string());
+	}
+
+	Ok((host.to_string(), zone, port))
+}
+
+fn percent_decode(s: &str) -> Result<String, String> {
+	let bytes = s.as_bytes();
+	let mut decoded: Vec<u8> = Vec::with_capacity(bytes.len());
+	let mut i = 0;
+	while i < bytes.len() {
+		match bytes[i] {
+			b'%' => {
+				let byte = s
+					.get(i + 1..i + 3)
+					.and_then(|hex| u8::from_str_radix(hex, 16).ok())
+					.ok_or_else(|| format!("invalid escape in '{}'", s))?;
+				decoded.push(byte);
+				i += 3;
+			},
+			b => {
+				decoded.push(b);
+				i += 1;
+			},
+		}
+	}
+	String::from_utf8(decoded).map_err(|_| format!("'{}' isn't valid UTF-8", s))
+}
+
+/// Maps the zone of an IPv6 address, an interface name or index, to the scope id.
+fn zone_index(zone: &str) -> io::Result<u32> {
+	if let Ok(index) = zone.parse::<u32>() {
+		return Ok(index);
+	}
+
+	#[cfg(target_os = "linux")]
+	{
+		let name = std::ffi::CString::new(zone).map_err(|_| invalid("invalid interface name"))?;
+		match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+			0 => Err(io::Error::new(io::ErrorKind::NotFound, format!("no interface named '{}'", zone))),
+			index => Ok(index),
+		}
+	}
+	#[cfg(not(target_os = "linux"))]
+	Err(invalid("only interface indexes are supported as zone on this platform"))
+}
+
+fn invalid(msg: &str) -> io::Error {
+	io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
@@ -0,0 +1,280 @@
+use std::fmt::Display;
+use std::io::{self, Read};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{Context, Poll};
+
+use sha2::Digest;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+///
+/// Digest algorithms to check transferred files with. TFTP itself has nothing but
+/// the UDP checksum.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+	Sha256,
+	Md5,
+	Crc32,
+}
+impl Algorithm {
+	/// Length of the digest in bytes.
+	pub fn digest_len(&self) -> usize {
+		match self {
+			Self::Sha256 => 32,
+			Self::Md5 => 16,
+			Self::Crc32 => 4,
+		}
+	}
+	/// Extension of sidecar files holding the digest, e.g. `firmware.bin.sha256`.
+	pub fn extension(&self) -> &'static str {
+		match self {
+			Self::Sha256 => "sha256",
+			Self::Md5 => "md5",
+			Self::Crc32 => "crc32",
+		}
+	}
+}
+impl Display for Algorithm {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "{}", self.extension())
+	}
+}
+impl FromStr for Algorithm {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s.to_ascii_lowercase().as_str() {
+			"sha256" | "sha-256" => Ok(Self::Sha256),
+			"md5" => Ok(Self::Md5),
+			"crc32" => Ok(Self::Crc32),
+			_ => Err(format!("unknown digest algorithm '{}', expected sha256, md5 or crc32", s)),
+		}
+	}
+}
+
+///
+/// A digest along with its algorithm. Parsed from and shown as `ALGORITHM:HEX`, e.g.
+/// `crc32:cbf43926`; when parsing, the algorithm may be left out if the length of the
+/// digest tells it.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Checksum {
+	algorithm: Algorithm,
+	value: Vec<u8>,
+}
+impl Checksum {
+	pub fn new(algorithm: Algorithm, value: Vec<u8>) -> Result<Self, String> {
+		match value.len() == algorithm.digest_len() {
+			true => Ok(Self { algorithm, value }),
+			false => Err(format!("a {} digest has {} bytes, got {}", algorithm, algorithm.digest_len(), value.len())),
+		}
+	}
+
+	///
+	/// Reads the digest from a sidecar file as written by `sha256sum` and friends:
+	/// the digest in hex, optionally followed by the file name.
+	///
+	pub fn from_sidecar(algorithm: Algorithm, content: &str) -> Result<Self, String> {
+		let hex = content
+			.split_whitespace()
+			.next()
+			.ok_or_else(|| format!("the {} sidecar file is empty", algorithm))?;
+		Self::new(algorithm, decode_hex(hex)?)
+	}
+
+	pub fn algorithm(&self) -> Algorithm { self.algorithm }
+	pub fn value(&self) -> &[u8] { &self.value }
+
+	pub fn to_hex(&self) -> String {
+		self.value.iter().map(|b| format!("{:02x}", b)).collect()
+	}
+}
+impl Display for Checksum {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "{}:{}", self.algorithm, self.to_hex())
+	}
+}
+impl FromStr for Checksum {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let (algorithm, hex) = match s.split_once(':') {
+			Some((algorithm, hex)) => (algorithm.parse::<Algorithm>()?, hex),
+			None => {
+				let algorithm = [Algorithm::Sha256, Algorithm::Md5, Algorithm::Crc32]
+					.into_iter()
+					.find(|a| a.digest_len() * 2 == s.len())
+					.ok_or_else(|| format!("can't tell the algorithm of '{}', use ALGORITHM:HEX", s))?;
+				(algorithm, s)
+			},
+		};
+		Self::new(algorithm, decode_hex(hex)?)
+	}
+}
+
+fn decode_hex(hex: &str) -> Result<Vec<u8>, String> {
+	if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+		return Err(format!("'{}' isn't a hex string", hex));
+	}
+	(0..hex.len())
+		.step_by(2)
+		.map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| format!("'{}' isn't a hex string", hex)))
+		.collect()
+}
+
+///
+/// Computes a digest incrementally.
+///
+#[derive(Debug, Clone)]
+pub struct Hasher {
+	state: State,
+}
+
+#[derive(Debug, Clone)]
+enum State {
+	Sha256(sha2::Sha256),
+	Md5(md5::Md5),
+	Crc32(crc32fast::Hasher),
+}
+
+impl Hasher {
+	pub fn new(algorithm: Algorithm) -> Self {
+		let state = match algorithm {
+			Algorithm::Sha256 => State::Sha256(sha2::Sha256::new()),
+			Algorithm::Md5 => State::Md5(md5::Md5::new()),
+			Algorithm::Crc32 => State::Crc32(crc32fast::Hasher::new()),
+		};
+		Self { state }
+	}
+
+	pub fn update(&mut self, data: &[u8]) {
+		match &mut self.state {
+			State::Sha256(h) => h.update(data),
+			State::Md5(h) => h.update(data),
+			State::Crc32(h) => h.update(data),
+		}
+	}
+
+	pub fn finish(self) -> Checksum {
+		let (algorithm, value) = match self.state {
+			State::Sha256(h) => (Algorithm::Sha256, h.finalize().to_vec()),
+			State::Md5(h) => (Algorithm::Md5, h.finalize().to_vec()),
+			State::Crc32(h) => (Algorithm::Crc32, h.finalize().to_be_bytes().to_vec()),
+		};
+		Checksum { algorithm, value }
+	}
+
+	/// Digest of everything `reader` yields.
+	pub fn digest_reader(algorithm: Algorithm, mut reader: impl Read) -> io::Result<Checksum> {
+		let mut hasher = Self::new(algorithm);
+		let mut buf = vec![0u8; 64 * 1024];
+		loop {
+			match reader.read(&mut buf)? {
+				0 => return Ok(hasher.finish()),
+				n => hasher.update(&buf[..n]),
+			}
+		}
+	}
+}
+
+// ############################################################################
+// ############################################################################
+// ############################################################################
+
+///
+/// Wraps the stream of a transfer and hashes everything read from or written to it,
+/// if there is a hasher.
+///
+pub(crate) struct HashingStream<T> {
+	inner: T,
+	hasher: Option<Hasher>,
+}
+impl<T> HashingStream<T> {
+	pub fn new(inner: T, algorithm: Option<Algorithm>) -> Self {
+		Self { inner, hasher: algorithm.map(Hasher::new) }
+	}
+	pub fn finish(self) -> Option<Checksum> {
+		self.hasher.map(Hasher::finish)
+	}
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for HashingStream<T> {
+	fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+		let filled = buf.filled().len();
+		let this = &mut *self;
+		let res = Pin::new(&mut this.inner).poll_read(cx, buf);
+		if let (Poll::Ready(Ok(())), Some(hasher)) = (&res, this.hasher.as_mut()) {
+			hasher.update(&buf.filled()[filled..]);
+		}
+		res
+	}
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for HashingStream<T> {
+	fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+		let this = &mut *self;
+		let res = Pin::new(&mut this.inner).poll_write(cx, buf);
+		if let (Poll::Ready(Ok(n)), Some(hasher)) = (&res, this.hasher.as_mut()) {
+			hasher.update(&buf[..*n]);
+		}
+		res
+	}
+	fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		Pin::new(&mut self.inner).poll_flush(cx)
+	}
+	fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		Pin::new(&mut self.inner).poll_shutdown(cx)
+	}
+}
+
+// ############################################################################
+// #### TESTS #################################################################
+// ############################################################################
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Two blocks once padded, from FIPS 180-2 and RFC 1321's test suite.
+	const NIST_56: &[u8] = b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq";
+
+	fn digest(algorithm: Algorithm, data: &[u8]) -> String {
+		let mut hasher = Hasher::new(algorithm);
+		hasher.update(data);
+		hasher.finish().to_hex()
+	}
+
+	#[test]
+	fn known_answers() {
+		assert_eq!(digest(Algorithm::Sha256, b""), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+		assert_eq!(digest(Algorithm::Sha256, b"abc"), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+		assert_eq!(digest(Algorithm::Sha256, NIST_56), "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1");
+
+		assert_eq!(digest(Algorithm::Md5, b""), "d41d8cd98f00b204e9800998ecf8427e");
+		assert_eq!(digest(Algorithm::Md5, b"abc"), "900150983cd24fb0d6963f7d28e17f72");
+		assert_eq!(digest(Algorithm::Md5, NIST_56), "8215ef0796a20bcaaae116d3876c664a");
+
+		assert_eq!(digest(Algorithm::Crc32, b""), "00000000");
+		assert_eq!(digest(Algorithm::Crc32, b"123456789"), "cbf43926");
+	}
+
+	#[test]
+	fn chunked_updates_match_a_single_one() {
+		let data: Vec<u8> = (0..1000u32).map(|i| (i * 7 % 251) as u8).collect();
+		for algorithm in [Algorithm::Sha256, Algorithm::Md5, Algorithm::Crc32] {
+			let mut hasher = Hasher::new(algorithm);
+			/* Chunks ending before, on and after the 64 byte block boundaries */
+			let mut rest = &data[..];
+			for size in [1, 63, 64, 65, 127, 3].iter().cycle() {
+				let (chunk, tail) = rest.split_at((*size).min(rest.len()));
+				hasher.update(chunk);
+				rest = tail;
+				if rest.is_empty() {
+					break;
+				}
+			}
+			assert_eq!(hasher.finish().to_hex(), digest(algorithm, &data), "{}", algorithm);
+		}
+	}
+}
@@ -1,12 +1,18 @@
 use std::fmt::Display;
 use thiserror::Error;
 
+use crate::tftp::checksum::Checksum;
 use crate::tftp::consts;
 
 #[derive(Debug, Error)]
 pub enum RequestError {
 	#[error("received response from an unknown peer")]
 	UnknownPeer,
+	#[error("the server didn't respond to the request")]
+	NoResponse,
+	/// Sending the request or waiting for its reply failed, e.g. the network was unreachable.
+	#[error("the server could not be reached: {0}")]
+	Unreachable(std::io::Error),
 	#[error("the requested file could not be found")]
 	FileNotFound,
 	#[error("the file is not accessible for reading/writing")]
@@ -15,9 +21,13 @@ pub enum RequestError {
 	OptionNegotiationFailed(#[from] OptionError),
 	#[error("")]
 	MalformedRequest,
-	#[error("")]
+	#[error("the server announced {0} bytes, but {1} were received")]
+	SizeMismatch(u64, u64),
+	#[error("checksum mismatch, expected {0} but got {1}")]
+	ChecksumMismatch(Checksum, Checksum),
+	#[error("{0}")]
 	ConnectionError(#[from] ConnectionError),
-	#[error("")]
+	#[error("{0}")]
 	OtherHostError(#[from] std::io::Error)
 }
 
@@ -35,7 +45,7 @@ pub enum ConnectionError {
 	UnknownTid,
 	#[error("peer requested an unsupported transfer mode")]
 	UnsupportedTxMode,
-	#[error("")]
+	#[error("peer sent error {:?} {}", .0.code(), .0.msg())]
 	PeerError(#[from] TftpError),
 	#[error("response is invalid: {0}")]
 	InvalidResponse(#[from] ParseError),
@@ -76,8 +86,12 @@ impl From<std::str::Utf8Error> for ParseError {
 pub enum OptionError {
 	#[error("the option is invalid")]
 	InvalidOption,
+	#[error("peer acknowledged an option that wasn't requested")]
+	Unrequested,
 	#[error("client didn't acknowledge options")]
 	NoAck,
+	#[error("peer refused the options")]
+	Refused,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -122,6 +136,13 @@ pub struct TftpError {
 	code: ErrorCode,
 	msg: Box<str>
 }
+impl TftpError {
+	pub fn code(&self) -> ErrorCode { self.code }
+	pub fn msg(&self) -> &str { &self.msg }
+
+	/// ERROR 8 is how a peer refuses the options we asked for (RFC 2347).
+	pub fn is_option_refusal(&self) -> bool { self.code == ErrorCode::InvalidOption }
+}
 impl Display for TftpError {
 	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
 		write!(f, "{};{}", self.code, self.msg)
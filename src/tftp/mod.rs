@@ -1,12 +1,19 @@
-use std::net::{UdpSocket, SocketAddr, IpAddr};
+use std::collections::VecDeque;
+use std::net::{SocketAddr, IpAddr};
+use std::sync::Arc;
 use std::str::FromStr;
-use std::{fmt::Display, time::Duration};
-use std::io::{self, Read, Write, BufReader, BufWriter};
+use std::{fmt::Display, time::{Duration, Instant}};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::UdpSocket;
 
 pub mod packet;
 pub mod options;
 pub mod utils;
 pub mod error;
+pub mod multicast;
+pub mod stats;
+pub mod checksum;
 
 pub type Result<T> = std::result::Result<T, ConnectionError>;
 
@@ -17,16 +24,25 @@ use tokio_util::sync::CancellationToken;
 pub mod consts {
 	pub const TFTP_LISTEN_PORT: u16 = 69;
 	pub const DEFAULT_BLOCK_SIZE: u16 = 512;
+	pub const MIN_BLOCK_SIZE: u16 = 8;
+	pub const MAX_BLOCK_SIZE: u16 = 65464;
 	pub const DEFAULT_TIMEOUT_SECS: u8 = 5;
+	pub const MIN_UTIMEOUT_USECS: u32 = 10_000;
+	pub const MAX_UTIMEOUT_USECS: u32 = 255_000_000;
 	pub const DEFAULT_RETRANSMIT_ATTEMPTS: u8 = 5;
+	/// Largest windowsize a server grants unless configured otherwise.
+	pub const DEFAULT_MAX_WINDOW_SIZE: u16 = 64;
+	pub const REQUEST_INITIAL_TIMEOUT_MS: u64 = 1000;
 
 	pub const TFTP_XFER_MODE_OCTET: &str = "octet";
 	pub const TFTP_XFER_MODE_NETASCII: &str = "netascii";
 
 	pub const OPT_BLOCKSIZE_IDENT: &str = "blksize";
 	pub const OPT_TIMEOUT_IDENT: &str = "timeout";
+	pub const OPT_UTIMEOUT_IDENT: &str = "utimeout";
 	pub const OPT_TRANSFERSIZE_IDENT: &str = "tsize";
 	pub const OPT_WINDOWSIZE_IDENT: &str = "windowsize";
+	pub const OPT_MULTICAST_IDENT: &str = "multicast";
 
 	pub const OPCODE_RRQ: u16 = 1;
 	pub const OPCODE_WRQ: u16 = 2;
@@ -51,6 +67,7 @@ pub mod consts {
 use packet::{self as pkt, builder::TftpErrorBuilder, Packet};
 use error::{ConnectionError, ErrorCode, ParseError};
 use options::*;
+use stats::{ProgressObserver, TransferCounters, TransferStats};
 
 
 // ############################################################################
@@ -107,20 +124,32 @@ pub struct TftpConnection {
 	socket: UdpSocket,
 
 	options: TftpOptions,
+	reply_timeout: Duration,
+	dally: Option<Duration>,
 	cxl_tok: CancellationToken,
+	counters: TransferCounters,
+	progress: Option<Arc<dyn ProgressObserver>>,
+	expected_size: Option<u64>,
 }
 
 impl TftpConnection {
 
+	/// Must be called from within a Tokio runtime, which drives the socket.
 	#[inline(always)]
 	pub fn new(local_addr: IpAddr, cxl_tok: CancellationToken) -> Result<Self> {
-		let socket = UdpSocket::bind(SocketAddr::new(local_addr, 0))?;
+		let socket = std::net::UdpSocket::bind(SocketAddr::new(local_addr, 0))?;
+		socket.set_nonblocking(true)?;
 
 		let mut conn = Self {
-			socket,
+			socket: UdpSocket::from_std(socket)?,
 			options: TftpOptions::default(),
+			reply_timeout: Duration::ZERO,
+			dally: None,
 			cxl_tok,
-			tx_mode: Mode::Octet
+			tx_mode: Mode::Octet,
+			counters: TransferCounters::new(),
+			progress: None,
+			expected_size: None,
 		};
 		conn.set_reply_timeout(conn.opt_timeout());
 		Ok(conn)
@@ -130,74 +159,132 @@ impl TftpConnection {
 	// ###### GETTER ##########################################################
 	// ########################################################################
 
-	#[inline(always)] pub fn tx_mode(&self) 			-> Mode 		{ self.tx_mode }
 	#[inline(always)] pub fn opt_blocksize(&self) 		-> u16 			{ self.options.blocksize }
 	#[inline(always)] pub fn opt_timeout(&self) 		-> Duration 	{ self.options.timeout }
-	#[inline(always)] pub fn opt_transfer_size(&self) 	-> u32 			{ self.options.transfer_size }
+	#[inline(always)] pub fn opt_windowsize(&self) 	-> u16 			{ self.options.windowsize }
+	#[inline(always)] pub fn options(&self)			-> &TftpOptions	{ &self.options }
+	#[inline(always)] pub fn dally_period(&self)		-> Duration		{ self.dally.unwrap_or_default() }
 	#[inline(always)] pub fn cancelled(&self) 			-> bool 		{ self.cxl_tok.is_cancelled() }
 	#[inline(always)] pub fn peer(&self)				-> SocketAddr	{ self.socket.peer_addr().unwrap() }
 
+	/// Statistics of the transfer so far, or of the whole transfer once it is finished.
+	pub fn stats(&self) -> TransferStats {
+		self.counters.stats(self.peer(), self.options.clone())
+	}
+
+	/// The largest blksize that avoids IP fragmentation towards the connected peer,
+	/// if the path MTU is known.
+	pub fn mtu_blocksize(&self) -> Option<u16> {
+		let mtu = utils::path_mtu((&self.socket).into())?;
+		Some(utils::blocksize_for_mtu(mtu, &self.socket.peer_addr().ok()?))
+	}
+
 	// ########################################################################
 	// ###### SETTER ##########################################################
 	// ########################################################################
 
+	/// Sets the timeout used unless another one is negotiated.
+	pub fn set_default_timeout(&mut self, timeout: Duration) {
+		self.options.timeout = timeout;
+		self.set_reply_timeout(timeout);
+	}
+
 	pub fn set_reply_timeout(&mut self, timeout: Duration) {
-		self.socket.set_nonblocking(false).ok();
-		self.socket.set_read_timeout(Some(timeout)).ok();
+		self.reply_timeout = timeout;
 		debug!("Timeout set to {}ms", timeout.as_millis());
 	}
 
-	pub fn set_tx_mode(&mut self, tx_mode: Mode) -> Result<()> {
+	/// Sets how long the receiving side lingers after acknowledging the final block.
+	/// `None` (the default) doesn't linger at all. RFC 1350 suggests the reply timeout,
+	/// which is what the peer waits before retransmitting.
+	pub fn set_dally_period(&mut self, dally: Option<Duration>) {
+		self.dally = dally;
+	}
+
+	pub fn set_progress_observer(&mut self, observer: Option<Arc<dyn ProgressObserver>>) {
+		self.progress = observer;
+	}
+
+	/// Size of the file being transferred, passed on to the progress observer.
+	pub fn set_expected_size(&mut self, size: Option<u64>) {
+		self.expected_size = size;
+	}
+
+	pub async fn set_tx_mode(&mut self, tx_mode: Mode) -> Result<()> {
 		if tx_mode != Mode::Octet {
-			self.send_error(ErrorCode::IllegalOperation, "NetAscii mode not supported").ok();
+			self.send_error(ErrorCode::IllegalOperation, "NetAscii mode not supported").await.ok();
 			return Err(ConnectionError::UnsupportedTxMode);
 		}
 		self.tx_mode = tx_mode;
 		Ok(())
 	}
 
-	pub fn set_options(&mut self, opts: &[TftpOption]) {
+	/// Applies negotiated options. Custom options are handed to their handler in
+	/// `registry`, if there is one.
+	pub fn set_options(&mut self, opts: &[TftpOption], registry: &OptionRegistry) {
 		for opt in opts {
 			match opt {
 				TftpOption::Blocksize(bs) => self.options.blocksize = *bs,
-				TftpOption::Timeout(t) => self.options.timeout = *t,
+				TftpOption::Timeout(t) | TftpOption::UTimeout(t) => self.options.timeout = *t,
 				TftpOption::TransferSize(ts) => self.options.transfer_size = *ts,
+				TftpOption::WindowSize(ws) => self.options.windowsize = *ws,
+				TftpOption::Multicast(mc) => self.options.multicast = *mc,
+				TftpOption::Custom(name, value) => {
+					self.options.custom.retain(|(key, _)| !key.eq_ignore_ascii_case(name));
+					self.options.custom.push((name.clone(), value.clone()));
+				},
 			}
 		}
+		registry.apply(&mut self.options);
 
 		self.set_reply_timeout(self.opt_timeout());
 	}
 
+	/// Keeps options requested by the peer that nobody handles, so they are still
+	/// available from `options()`.
+	pub fn set_unknown_options(&mut self, unknown: Vec<(String, String)>) {
+		self.options.unknown = unknown;
+	}
+
 	// ########################################################################
 	// ###### ACTIONS #########################################################
 	// ########################################################################
 
-	pub fn connect_to(&self, to: SocketAddr) -> Result<()> {
-		Ok(self.socket.connect(to)?)
-	}
-
-	pub fn receive_packet_from<'a>(&self, buf: &'a mut [u8]) -> Result<(packet::TftpPacket<'a>, SocketAddr)> {
-		let pkt: packet::TftpPacket;
-		
-		match self.socket.recv_from(buf) {
-			Ok((len, tx)) => {
-				pkt = packet::TftpPacket::try_from_buf(&buf[..len])?;
-				Ok((pkt, tx))
-			}, 
-			Err(e) => {
-				match e.kind() {
-					io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => Err(ConnectionError::Timeout),
-					_ => Err(e.into()),
-				}
+	/// Accounts for a block that was transferred successfully.
+	fn block_done(&self, len: usize) {
+		let transferred = self.counters.block(len);
+		if let Some(observer) = self.progress.as_ref() {
+			observer.progress(transferred, self.expected_size);
+		}
+	}
+
+	pub async fn connect_to(&self, to: SocketAddr) -> Result<()> {
+		Ok(self.socket.connect(to).await?)
+	}
+
+	/// Waits up to `timeout` for a datagram. Gives up early if the connection is cancelled.
+	async fn recv_from_within(&self, buf: &mut [u8], timeout: Duration) -> Result<(usize, SocketAddr)> {
+		tokio::select! {
+			_ = self.cxl_tok.cancelled() => Err(ConnectionError::Cancelled),
+			recv = tokio::time::timeout(timeout, self.socket.recv_from(buf)) => match recv {
+				Ok(recv) => Ok(recv?),
+				Err(_) => Err(ConnectionError::Timeout),
 			},
 		}
 	}
 
-	pub fn receive_packet<'a>(&self, buf: &'a mut [u8]) -> Result<packet::TftpPacket<'a>> {
-		let recv = self.receive_packet_from(buf)?;
+	async fn receive_packet_from<'a>(&self, buf: &'a mut [u8], timeout: Duration) -> Result<(packet::TftpPacket<'a>, SocketAddr)> {
+		let (len, tx) = self.recv_from_within(buf, timeout).await?;
+		trace!("received {} from {}", packet::describe(&buf[..len]), tx);
+		Ok((packet::TftpPacket::try_from_buf(&buf[..len])?, tx))
+	}
+
+	/// Waits up to `timeout` for a packet from the peer the connection is connected to.
+	async fn receive_packet_within<'a>(&self, buf: &'a mut [u8], timeout: Duration) -> Result<packet::TftpPacket<'a>> {
+		let recv = self.receive_packet_from(buf, timeout).await?;
 		if let Ok(peer) = self.socket.peer_addr() {
 			if peer != recv.1 { /* IP and port must be the same for whole connection */
-				self.send_error(ErrorCode::UnknownTid, "").ok();
+				self.send_error(ErrorCode::UnknownTid, "").await.ok();
 				return Err(ConnectionError::UnknownTid);
 			}
 		}
@@ -205,42 +292,132 @@ impl TftpConnection {
 		Ok(recv.0)
 	}
 
-	pub fn send_request_to(&self, req: &packet::TftpReq<'_>, to: SocketAddr) -> Result<()> {
-		Ok(self.socket.send_to(req.as_bytes(), to).map(|_| ())?)
+	pub async fn send_request_to(&self, req: &packet::TftpReq<'_>, to: SocketAddr) -> Result<()> {
+		trace!("sending {} to {}", packet::describe(req.as_bytes()), to);
+		Ok(self.socket.send_to(req.as_bytes(), to).await.map(|_| ())?)
+	}
+
+	///
+	/// send_request_and_receive
+	/// 
+	/// Sends a request and waits for the first reply, which may come from any port.
+	/// The request is retransmitted with exponential backoff, starting at
+	/// `REQUEST_INITIAL_TIMEOUT_MS` and capped at the reply timeout.
+	pub async fn send_request_and_receive<'a>(
+		&self,
+		req: &packet::TftpReq<'_>,
+		to: SocketAddr,
+		buf: &'a mut [u8]
+	) -> Result<(packet::TftpPacket<'a>, SocketAddr)> {
+		let max_timeout = self.opt_timeout();
+		let mut timeout = Duration::from_millis(consts::REQUEST_INITIAL_TIMEOUT_MS).min(max_timeout);
+		let mut attempts: u8 = 0;
+
+		/* Only receive raw bytes in the loop; returning the parsed packet from inside of
+		 * it would upset the borrow checker (see send_and_wait_for_reply below). */
+		let recv = loop {
+			if self.cancelled() {
+				break Err(ConnectionError::Cancelled);
+			}
+
+			self.send_request_to(req, to).await?;
+			match self.recv_from_within(buf, timeout).await {
+				Ok(recv) => break Ok(recv),
+				Err(ConnectionError::Timeout) => {
+					self.counters.timeout();
+					if attempts >= consts::DEFAULT_RETRANSMIT_ATTEMPTS {
+						break Err(ConnectionError::Timeout);
+					}
+					attempts += 1;
+					self.counters.retransmission();
+					timeout = (timeout * 2).min(max_timeout);
+					debug!("no reply to {} from {}, retransmitting", req.kind(), to);
+				},
+				Err(e) => break Err(e),
+			}
+		};
+
+		let (len, remote) = recv?;
+		trace!("received {} from {}", packet::describe(&buf[..len]), remote);
+		Ok((packet::TftpPacket::try_from_buf(&buf[..len])?, remote))
+	}
+
+	pub async fn send_packet(&self, pkt: &(impl packet::Packet + Sync)) -> Result<()> {
+		trace!("sending {}", packet::describe(pkt.as_bytes()));
+		Ok(self.socket.send(pkt.as_bytes()).await.map(|_| ())?)
 	}
 
-	pub fn send_packet(&self, pkt: &impl packet::Packet) -> Result<()> {
-		Ok(self.socket.send(pkt.as_bytes()).map(|_| ())?)
+	///
+	/// send_and_receive_ack
+	///
+	/// Sends a DATA (or OACK, for block 0) packet and waits for the matching ACK. ACKs for earlier blocks
+	/// (delayed or duplicated on the way) are silently dropped instead of triggering
+	/// a retransmission, otherwise every duplicate ACK would double the DATA sent for
+	/// the rest of the transfer (Sorcerer's Apprentice syndrome, RFC 1123 4.2.3.1).
+	/// The packet is only retransmitted when no matching ACK arrives in time, counting
+	/// from when it was sent, so a stream of old ACKs can't hold the retransmission off.
+	pub async fn send_and_receive_ack(&self, tx_pkt: &(impl packet::Packet + Sync), blocknum: u16) -> Result<()> {
+		self.send_window_and_receive_ack(&[tx_pkt], blocknum).await.map(|_| ())
 	}
 
-	pub fn send_and_receive_ack<'a>(&self, data_pkt: &pkt::MutableTftpData) -> Result<()> {
+	///
+	/// send_window_and_receive_ack
+	///
+	/// Like `send_and_receive_ack`, but for a window of consecutive blocks starting
+	/// with `first` (RFC 7440). Any ACK within the window is accepted and returned, the
+	/// blocks after it are up to the caller to send again. The whole window is
+	/// retransmitted when no such ACK arrives in time.
+	async fn send_window_and_receive_ack(&self, window: &[&(dyn packet::Packet + Sync)], first: u16) -> Result<u16> {
 		let mut attempts: u8 = 0;
-		let mut buf: [u8; 32] = [0; 32];
+		let mut buf: [u8; 128] = [0; 128];
+
+		self.send_window(window).await?;
+		let mut deadline = Instant::now() + self.reply_timeout;
 		loop {
 			if self.cancelled() {
 				return Err(ConnectionError::Cancelled);
 			}
 
-			self.send_packet(data_pkt)?;
-			match self.receive_packet(&mut buf) {
+			let remaining = deadline.saturating_duration_since(Instant::now());
+			match self.receive_packet_within(&mut buf, remaining).await {
+				Ok(pkt::TftpPacket::Ack(ack)) if (ack.blocknum().wrapping_sub(first) as usize) < window.len() => {
+					return Ok(ack.blocknum());
+				},
 				Ok(pkt::TftpPacket::Ack(ack)) => {
-					if ack.blocknum() != data_pkt.blocknum() {
+					/* Anything within the last half of the sequence space is an old ACK,
+					 * everything else was never sent by us. */
+					if first.wrapping_sub(ack.blocknum()) > u16::MAX / 2 {
 						return Err(ConnectionError::UnexpectedBlockAck);
 					}
-					return Ok(())
+					trace!("ignoring duplicate ACK for block {}", ack.blocknum());
+					self.counters.duplicate();
 				},
 				Ok(pkt::TftpPacket::Err(error)) => return Err(ConnectionError::PeerError(error.into())),
 				Ok(_) => return Err(ConnectionError::UnexpectedPacket),
-				Err(e) => {
-					if attempts > consts::DEFAULT_RETRANSMIT_ATTEMPTS {
-						return Err(e);
+				Err(ConnectionError::Timeout) => {
+					self.counters.timeout();
+					if attempts >= consts::DEFAULT_RETRANSMIT_ATTEMPTS {
+						return Err(ConnectionError::Timeout);
 					}
 					attempts += 1;
-				}
+					debug!("timeout waiting for ACK {}, retransmitting", first.wrapping_add(window.len() as u16 - 1));
+					self.counters.retransmission();
+					self.send_window(window).await?;
+					deadline = Instant::now() + self.reply_timeout;
+				},
+				Err(e) => return Err(e),
 			}
 		}
 	}
 
+	async fn send_window(&self, window: &[&(dyn packet::Packet + Sync)]) -> Result<()> {
+		for pkt in window {
+			trace!("sending {}", packet::describe(pkt.as_bytes()));
+			self.socket.send(pkt.as_bytes()).await?;
+		}
+		Ok(())
+	}
+
 	/* This could be used when Rust's new borrow checker is stable/usable. The current one
 	 * complains about recv_buf being multiple times borrowed multiple times. However, when
 	 * looking at the code it should be perfectly fine to do it that way. With
@@ -272,7 +449,7 @@ impl TftpConnection {
 		}
 	} */
 
-	pub fn send_error(&self, code: ErrorCode, msg: &str) -> Result<()> {
+	pub async fn send_error(&self, code: ErrorCode, msg: &str) -> Result<()> {
 		let mut buf: [u8; 64] = [0; 64];
 		let err_pkt = TftpErrorBuilder::new()
 			.with_buf(&mut buf[..])
@@ -280,29 +457,65 @@ impl TftpConnection {
 			.error_msg(msg)
 			.build();
 
-		self.socket.send(err_pkt.as_bytes())?;
+		self.socket.send(err_pkt.as_bytes()).await?;
 		error!("Tftp error: code {}; '{}'", code, msg);
 		Ok(())
 	}
 
+	/// Tells the peer the transfer is off since reading or writing our side of it
+	/// failed, instead of leaving it to time out.
+	async fn abort(&self, e: std::io::Error) -> ConnectionError {
+		self.send_error(ErrorCode::NotDefined, "transfer aborted").await.ok();
+		e.into()
+	}
+
 	///
 	/// receive_data
 	/// 
 	/// This is used for RRQ in client mode and WRQ in server mode
-	pub async fn receive_data<'a>(&self, stream: impl Write, init_data: Option<pkt::TftpData<'a>>) -> Result<()> {
+	/// 
+	/// `init_reply` is the packet that was sent to start the transfer (e.g. an OACK) and
+	/// is retransmitted when the first block doesn't arrive in time; ACK 0 is assumed
+	/// if it is `None`. Like the ACKs of `send_and_receive_ack`, each block has to arrive
+	/// in time after the previous one, duplicates don't extend the wait.
+	/// 
+	/// With a negotiated windowsize only every last block of a window is acknowledged
+	/// (RFC 7440). A missing block is reported once by acknowledging the block before
+	/// it, after which the peer sends the window again from there.
+	pub async fn receive_data<'a>(
+		&self,
+		stream: impl AsyncWrite + Unpin,
+		init_data: Option<pkt::TftpData<'a>>,
+		mut init_reply: Option<&(dyn pkt::Packet + Sync)>,
+	) -> Result<TransferStats> {
 		let mut buf_write = BufWriter::new(stream);
 		let blocksize = self.opt_blocksize();
 		let mut blocknum: u16 = 0;
 		let mut data_buf: Vec<u8> = vec![0; 4 + (blocksize as usize)];
+		let mut attempts: u8 = 0;
+		let mut deadline = Instant::now() + self.reply_timeout;
+		let windowsize = self.opt_windowsize().max(1);
+		/* Blocks received since the last ACK, and whether a gap was reported already */
+		let mut unacked: u16 = 0;
+		let mut gap_acked = false;
 	
 		if let Some(first) = init_data {
-			buf_write.write_all(first.data())?;
+			if let Err(e) = buf_write.write_all(first.data()).await {
+				return Err(self.abort(e).await);
+			}
 			blocknum += 1;
+			self.block_done(first.data_len());
 			
 			let ack_pkt = pkt::MutableTftpAck::new(blocknum);
-			self.send_packet(&ack_pkt)?;
+			self.send_packet(&ack_pkt).await?;
+			deadline = Instant::now() + self.reply_timeout;
 			if first.data_len() < (blocksize as usize) {
-				return Ok(());
+				if let Err(e) = buf_write.flush().await {
+					return Err(self.abort(e).await);
+				}
+				self.counters.finish();
+				self.dally(&mut data_buf[..], blocknum).await;
+				return Ok(self.stats());
 			}
 		}
 	
@@ -311,69 +524,334 @@ impl TftpConnection {
 				return Err(ConnectionError::Cancelled)
 			}
 	
-			let pkt = match self.receive_packet(&mut data_buf[..]) {
+			let remaining = deadline.saturating_duration_since(Instant::now());
+			let pkt = match self.receive_packet_within(&mut data_buf[..], remaining).await {
 				Ok(pkt::TftpPacket::Data(data)) => data,
 				Ok(pkt::TftpPacket::Err(error)) => return Err(ConnectionError::PeerError(error.into())),
 				Ok(_) => return Err(ConnectionError::UnexpectedPacket),
+				Err(ConnectionError::Timeout) => {
+					self.counters.timeout();
+					if attempts >= consts::DEFAULT_RETRANSMIT_ATTEMPTS {
+						return Err(ConnectionError::Timeout);
+					}
+					attempts += 1;
+					self.counters.retransmission();
+					debug!("timeout waiting for block {}, retransmitting last reply", blocknum.wrapping_add(1));
+					match init_reply {
+						Some(reply) => self.socket.send(reply.as_bytes()).await.map(|_| ())?,
+						None => self.send_packet(&pkt::MutableTftpAck::new(blocknum)).await?,
+					}
+					unacked = 0;
+					deadline = Instant::now() + self.reply_timeout;
+					continue;
+				},
 				Err(e) => return Err(e),
 			};
-			if pkt.blocknum() != blocknum.wrapping_add(1) {
+			if pkt.blocknum() == blocknum {
+				/* The peer didn't get our last ACK and retransmitted the block */
+				self.counters.duplicate();
+				self.send_packet(&pkt::MutableTftpAck::new(blocknum)).await?;
+				unacked = 0;
+				continue;
+			} else if pkt.blocknum() != blocknum.wrapping_add(1) {
+				let ahead = pkt.blocknum().wrapping_sub(blocknum) <= u16::MAX / 2;
+				if windowsize > 1 && ahead && !gap_acked {
+					debug!("block {} missing, asking for the window again", blocknum.wrapping_add(1));
+					self.send_packet(&pkt::MutableTftpAck::new(blocknum)).await?;
+					gap_acked = true;
+					unacked = 0;
+					deadline = Instant::now() + self.reply_timeout;
+				}
 				continue;
 			}
 	
-			buf_write.write_all(pkt.data())?;
+			if let Err(e) = buf_write.write_all(pkt.data()).await {
+				return Err(self.abort(e).await);
+			}
 			blocknum = blocknum.wrapping_add(1);
+			self.block_done(pkt.data_len());
+			init_reply = None;
+			attempts = 0;
+			gap_acked = false;
+			unacked += 1;
+			deadline = Instant::now() + self.reply_timeout;
 			
-			let ack_pkt = packet::MutableTftpAck::new(blocknum);
-			self.send_packet(&ack_pkt)?;
-			if pkt.data_len() < (blocksize as usize) {
+			let last = pkt.data_len() < (blocksize as usize);
+			if unacked == windowsize || last {
+				self.send_packet(&packet::MutableTftpAck::new(blocknum)).await?;
+				unacked = 0;
+			}
+			if last {
 				break;
 			}
 		}
 	
-		buf_write.flush().ok();
+		if let Err(e) = buf_write.flush().await {
+			return Err(self.abort(e).await);
+		}
+		self.counters.finish();
 		debug!("received data");
-		Ok(())
+		self.dally(&mut data_buf[..], blocknum).await;
+		Ok(self.stats())
+	}
+
+	///
+	/// dally
+	///
+	/// Lingers after the final ACK has been sent and re-acknowledges the last block in
+	/// case the peer retransmits it, i.e. our final ACK got lost (RFC 1350, section 6).
+	/// The transfer is complete at this point, so nothing in here is treated as an error.
+	async fn dally(&self, buf: &mut [u8], last_blocknum: u16) {
+		let period = self.dally_period();
+		if period.is_zero() {
+			return;
+		}
+
+		let deadline = Instant::now() + period;
+		loop {
+			let remaining = deadline.saturating_duration_since(Instant::now());
+			if remaining.is_zero() || self.cancelled() {
+				break;
+			}
+			match self.receive_packet_within(buf, remaining).await {
+				Ok(pkt::TftpPacket::Data(data)) if data.blocknum() == last_blocknum => {
+					debug!("final block {} retransmitted by peer, ACKing again", last_blocknum);
+					if self.send_packet(&pkt::MutableTftpAck::new(last_blocknum)).await.is_err() {
+						break;
+					}
+				},
+				Ok(_) | Err(ConnectionError::UnknownTid) => (),
+				Err(_) => break,
+			}
+		}
 	}
 
 	///
 	/// send_data
 	/// 
-	/// This is used for RRQ in server mode and WRQ in client mode
-	pub async fn send_data(&self, stream: impl Read) -> Result<()> {
+	/// This is used for RRQ in server mode and WRQ in client mode. Up to windowsize
+	/// blocks are sent before waiting for an ACK (RFC 7440).
+	pub async fn send_data(&self, stream: impl AsyncRead + Unpin) -> Result<TransferStats> {
 		let blocksize = self.opt_blocksize();
+		let windowsize = self.opt_windowsize().max(1) as usize;
 		let mut buf_read = BufReader::new(stream);
-		//debug!("start sending file");
 
-		/* Use only one buffer for file read and packet send. The first 4 bytes are always reserved
-		 * for packet header and the file is read after that. */
-		let mut read_buf: Vec<u8> = Vec::with_capacity(4 + (blocksize as usize));
+		/* Blocks sent but not acknowledged yet. The first 4 bytes of each buffer are
+		 * reserved for the packet header and the file is read after that. */
+		let mut window: VecDeque<pkt::TftpData<'static>> = VecDeque::with_capacity(windowsize);
 		let mut sent_blocks: usize = 0;
-		let mut blocknum: u16 = 0;
-
-		read_buf.extend([0; 4]);
+		let mut next_blocknum: u16 = 1;
+		let mut read_all = false;
 		loop {
 			if self.cancelled() {
 				return Err(ConnectionError::Cancelled);
 			}
 
-			let bytes_available = buf_read.by_ref().take(blocksize as u64).read_to_end(&mut read_buf)?;
-			let mut pkt = packet::MutableTftpData::from(&mut read_buf[..]);
-			
-			blocknum = blocknum.wrapping_add(1);
-			pkt.set_blocknum(blocknum as u16);
-			
-			self.send_and_receive_ack(&pkt)?;
+			while window.len() < windowsize && !read_all {
+				let mut read_buf: Vec<u8> = Vec::with_capacity(4 + (blocksize as usize));
+				read_buf.extend([0; 4]);
+				let bytes_available = match (&mut buf_read).take(blocksize as u64).read_to_end(&mut read_buf).await {
+					Ok(n) => n,
+					Err(e) => return Err(self.abort(e).await),
+				};
+				packet::MutableTftpData::from(&mut read_buf[..]).set_blocknum(next_blocknum);
+				window.push_back(pkt::TftpData::from_owned(read_buf));
+
+				next_blocknum = next_blocknum.wrapping_add(1);
+				/* Stop after the last block */
+				read_all = bytes_available < (blocksize as usize);
+			}
 
-			sent_blocks += 1;
-			if bytes_available < (blocksize as usize) {
-				/* Stop if this was the last block */
+			let first = window[0].blocknum();
+			let packets: Vec<&(dyn packet::Packet + Sync)> = window.iter().map(|e| e as _).collect();
+			let acked = self.send_window_and_receive_ack(&packets, first).await?;
+			for block in window.drain(..=(acked.wrapping_sub(first) as usize)) {
+				self.block_done(block.data_len());
+				sent_blocks += 1;
+			}
+			if read_all && window.is_empty() {
 				break;
 			}
-			read_buf.truncate(4);
 		}
 
+		self.counters.finish();
 		debug!("sent file in {} blocks", sent_blocks);
-		Ok(())
+		Ok(self.stats())
+	}
+}
+
+// ############################################################################
+// #### TESTS #################################################################
+// ############################################################################
+
+#[cfg(test)]
+mod tests {
+	use std::net::Ipv4Addr;
+
+	use super::*;
+
+	/// A connection and a socket playing its peer, both on loopback.
+	async fn connected_pair(timeout: Duration) -> (TftpConnection, UdpSocket) {
+		let peer = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+		let mut conn = TftpConnection::new(Ipv4Addr::LOCALHOST.into(), CancellationToken::new()).unwrap();
+		conn.set_default_timeout(timeout);
+		conn.connect_to(peer.local_addr().unwrap()).await.unwrap();
+		peer.connect(conn.socket.local_addr().unwrap()).await.unwrap();
+		(conn, peer)
+	}
+
+	/// Block number of the DATA packet arriving within `timeout`, if any.
+	async fn receive_data(peer: &UdpSocket, timeout: Duration) -> Option<u16> {
+		let mut buf = [0u8; 64];
+		let len = tokio::time::timeout(timeout, peer.recv(&mut buf)).await.ok()?.unwrap();
+		match pkt::TftpPacket::try_from_buf(&buf[..len]) {
+			Ok(pkt::TftpPacket::Data(data)) => Some(data.blocknum()),
+			_ => panic!("peer expected DATA"),
+		}
+	}
+
+	/// Block number of the ACK arriving within `timeout`, if any.
+	async fn receive_ack(peer: &UdpSocket, timeout: Duration) -> Option<u16> {
+		let mut buf = [0u8; 64];
+		let len = tokio::time::timeout(timeout, peer.recv(&mut buf)).await.ok()?.unwrap();
+		match pkt::TftpPacket::try_from_buf(&buf[..len]) {
+			Ok(pkt::TftpPacket::Ack(ack)) => Some(ack.blocknum()),
+			_ => panic!("peer expected ACK"),
+		}
+	}
+
+	async fn send_ack(peer: &UdpSocket, blocknum: u16) {
+		peer.send(pkt::MutableTftpAck::new(blocknum).as_bytes()).await.unwrap();
+	}
+
+	async fn send_block(peer: &UdpSocket, blocknum: u16, data: &[u8]) {
+		let mut buf = vec![0u8; 4 + data.len()];
+		peer.send(pkt::MutableTftpData::with(&mut buf, blocknum, data).as_bytes()).await.unwrap();
+	}
+
+	/// Blocks of 8 bytes, sent or received 4 at a time.
+	fn set_window_options(conn: &mut TftpConnection) {
+		conn.set_options(&[TftpOption::Blocksize(8), TftpOption::WindowSize(4)], &OptionRegistry::default());
+	}
+
+	#[tokio::test]
+	async fn stale_and_duplicate_acks_dont_cause_retransmissions() {
+		let (conn, peer) = connected_pair(Duration::from_secs(2)).await;
+		let mut buf = [0u8; 12];
+		let data = pkt::MutableTftpData::with(&mut buf, 2, b"block #2");
+
+		let peer_side = async {
+			assert_eq!(receive_data(&peer, Duration::from_secs(1)).await, Some(2));
+			/* The ACK of the previous block arrives late, and duplicated */
+			for _ in 0..3 {
+				send_ack(&peer, 1).await;
+			}
+			assert_eq!(receive_data(&peer, Duration::from_millis(500)).await, None, "DATA sent again");
+			send_ack(&peer, 2).await;
+		};
+		let (res, ()) = tokio::join!(conn.send_and_receive_ack(&data, 2), peer_side);
+
+		res.unwrap();
+		let stats = conn.stats();
+		assert_eq!(stats.retransmissions, 0);
+		assert_eq!(stats.duplicates, 3);
+	}
+
+	#[tokio::test]
+	async fn data_is_retransmitted_on_timeout() {
+		let (conn, peer) = connected_pair(Duration::from_millis(200)).await;
+		let mut buf = [0u8; 12];
+		let data = pkt::MutableTftpData::with(&mut buf, 2, b"block #2");
+
+		let peer_side = async {
+			assert_eq!(receive_data(&peer, Duration::from_secs(1)).await, Some(2));
+			/* Without an ACK the block comes again once the timeout expired */
+			assert_eq!(receive_data(&peer, Duration::from_secs(1)).await, Some(2));
+			send_ack(&peer, 2).await;
+		};
+		let (res, ()) = tokio::join!(conn.send_and_receive_ack(&data, 2), peer_side);
+
+		res.unwrap();
+		let stats = conn.stats();
+		assert_eq!(stats.retransmissions, 1);
+		assert_eq!(stats.timeouts, 1);
+		assert_eq!(stats.duplicates, 0);
+	}
+	#[tokio::test]
+	async fn stale_acks_dont_hold_off_the_retransmission() {
+		let timeout = Duration::from_millis(250);
+		let (conn, peer) = connected_pair(timeout).await;
+		let mut buf = [0u8; 12];
+		let data = pkt::MutableTftpData::with(&mut buf, 2, b"block #2");
+
+		let peer_side = async {
+			assert_eq!(receive_data(&peer, Duration::from_secs(1)).await, Some(2));
+			/* Like a receiver re-sending its last ACK on its own timeout, just quicker than ours */
+			let interval = timeout.mul_f64(0.8);
+			let retransmitted = loop {
+				if let Some(blocknum) = receive_data(&peer, interval).await {
+					break blocknum;
+				}
+				send_ack(&peer, 1).await;
+			};
+			assert_eq!(retransmitted, 2);
+			send_ack(&peer, 2).await;
+		};
+		let (res, ()) = tokio::time::timeout(Duration::from_secs(5), async {
+			tokio::join!(conn.send_and_receive_ack(&data, 2), peer_side)
+		}).await.expect("DATA was never retransmitted");
+
+		res.unwrap();
+		let stats = conn.stats();
+		assert_eq!(stats.retransmissions, 1);
+		assert!(stats.duplicates >= 1);
+	}
+
+	#[tokio::test]
+	async fn window_is_sent_again_after_the_last_acked_block() {
+		let (mut conn, peer) = connected_pair(Duration::from_secs(2)).await;
+		set_window_options(&mut conn);
+		let file: Vec<u8> = (0..30).collect();
+
+		let peer_side = async {
+			for blocknum in 1..=4 {
+				assert_eq!(receive_data(&peer, Duration::from_secs(1)).await, Some(blocknum));
+			}
+			/* Block 2 got lost on the way */
+			send_ack(&peer, 1).await;
+			for blocknum in 2..=4 {
+				assert_eq!(receive_data(&peer, Duration::from_secs(1)).await, Some(blocknum));
+			}
+			send_ack(&peer, 4).await;
+		};
+		let (res, ()) = tokio::join!(conn.send_data(&file[..]), peer_side);
+
+		let stats = res.unwrap();
+		assert_eq!(stats.bytes, 30);
+		assert_eq!(stats.blocks, 4);
+		assert_eq!(stats.retransmissions, 0);
+	}
+
+	#[tokio::test]
+	async fn missing_block_is_reported_once_per_window() {
+		let (mut conn, peer) = connected_pair(Duration::from_secs(2)).await;
+		set_window_options(&mut conn);
+		let mut received: Vec<u8> = Vec::new();
+
+		let peer_side = async {
+			send_block(&peer, 1, b"block #1").await;
+			send_block(&peer, 3, b"block #3").await;
+			send_block(&peer, 4, b"block #4").await;
+			assert_eq!(receive_ack(&peer, Duration::from_secs(1)).await, Some(1));
+			assert_eq!(receive_ack(&peer, Duration::from_millis(300)).await, None, "gap reported twice");
+			send_block(&peer, 2, b"block #2").await;
+			send_block(&peer, 3, b"block #3").await;
+			send_block(&peer, 4, b"last").await;
+			assert_eq!(receive_ack(&peer, Duration::from_secs(1)).await, Some(4));
+		};
+		let (res, ()) = tokio::join!(conn.receive_data(&mut received, None, None), peer_side);
+
+		res.unwrap();
+		assert_eq!(received, b"block #1block #2block #3last");
 	}
 }
@@ -0,0 +1,284 @@
+use std::fmt::Display;
+use std::io::{self, Seek, SeekFrom, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+#[allow(unused)]
+use log::{info, warn, error, debug, trace};
+
+use crate::tftp::error::{ConnectionError, OptionError, ParseError};
+use crate::tftp::options::TftpOption;
+use crate::tftp::stats::TransferStats;
+use crate::tftp::{consts, packet as pkt, Result, TftpConnection};
+
+/// How long to wait on the group socket before looking at the unicast socket again.
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+///
+/// Value of the multicast option in an OACK (RFC 2090): `addr,port,mc`.
+///
+/// Address and port may be left empty in OACKs that only change the master
+/// status of a client that already joined the group.
+///
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MulticastParams {
+	pub group: Option<SocketAddrV4>,
+	/// The master client is the one which ACKs the data sent to the group.
+	pub master: bool,
+}
+
+impl Display for MulticastParams {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self.group {
+			Some(group) => write!(f, "{},{},", group.ip(), group.port())?,
+			None => write!(f, ",,")?,
+		}
+		write!(f, "{}", if self.master { 1 } else { 0 })
+	}
+}
+
+impl FromStr for MulticastParams {
+	type Err = OptionError;
+
+	fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+		let mut parts = s.split(',');
+		let (Some(addr), Some(port), Some(mc), None) = (parts.next(), parts.next(), parts.next(), parts.next()) else {
+			return Err(OptionError::InvalidOption);
+		};
+
+		let group = match (addr.trim(), port.trim()) {
+			("", "") => None,
+			(addr, port) => {
+				let addr = addr.parse::<Ipv4Addr>().map_err(|_| OptionError::InvalidOption)?;
+				let port = port.parse::<u16>().map_err(|_| OptionError::InvalidOption)?;
+				if !addr.is_multicast() || port == 0 {
+					return Err(OptionError::InvalidOption);
+				}
+				Some(SocketAddrV4::new(addr, port))
+			},
+		};
+		let master = match mc.trim() {
+			"0" => false,
+			"1" => true,
+			_ => return Err(OptionError::InvalidOption),
+		};
+
+		Ok(Self { group, master })
+	}
+}
+
+///
+/// Opens a socket receiving the datagrams sent to `group`, joined on the interface
+/// with address `iface`.
+///
+/// Several receivers on the same host may join the same group, e.g. when testing on
+/// loopback, hence the address reuse.
+///
+pub fn join_group(group: SocketAddrV4, iface: Ipv4Addr) -> io::Result<UdpSocket> {
+	let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+	socket.set_reuse_address(true)?;
+
+	/* Binding to the group address filters out other traffic to that port on unix;
+	 * Windows only allows binding to a local address. */
+	#[cfg(unix)]
+	let bind_addr = group;
+	#[cfg(not(unix))]
+	let bind_addr = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, group.port());
+
+	socket.bind(&SocketAddr::V4(bind_addr).into())?;
+	socket.join_multicast_v4(group.ip(), &iface)?;
+	Ok(socket.into())
+}
+
+///
+/// Opens a socket for sending to multicast groups out of the interface with address
+/// `local`. Multicast loopback stays enabled so clients on the same host receive
+/// the data as well.
+///
+pub fn sender_socket(local: Ipv4Addr, ttl: u32) -> io::Result<UdpSocket> {
+	let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+	if !local.is_unspecified() {
+		socket.set_multicast_if_v4(&local)?;
+	}
+	socket.set_multicast_ttl_v4(ttl)?;
+	socket.set_multicast_loop_v4(true)?;
+	socket.bind(&SocketAddr::V4(SocketAddrV4::new(local, 0)).into())?;
+	Ok(socket.into())
+}
+
+impl TftpConnection {
+
+	///
+	/// receive_multicast
+	///
+	/// Client side of a multicast transfer (RFC 2090), started after the OACK carrying
+	/// `params` has been received. Data arrives on the group while the server's OACKs,
+	/// which may make us the master client, arrive on our unicast socket.
+	///
+	/// Blocks may arrive in any order when we joined a running session, so they are
+	/// written at their offset instead of appended.
+	///
+	pub async fn receive_multicast(&self, mut stream: impl Write + Seek, params: MulticastParams) -> Result<TransferStats> {
+		/* The first OACK must tell us where to listen */
+		let Some(group) = params.group else {
+			return Err(ParseError::MalformedPacket.into());
+		};
+		let IpAddr::V4(iface) = self.socket.local_addr()?.ip() else {
+			return Err(io::Error::from(io::ErrorKind::Unsupported).into());
+		};
+		let server_ip = self.peer().ip();
+		let mcast = join_group(group, iface)?;
+		mcast.set_nonblocking(true)?;
+		let mcast = tokio::net::UdpSocket::from_std(mcast)?;
+
+		let res = self.receive_multicast_blocks(&mut stream, &mcast, server_ip, params.master).await;
+
+		mcast.leave_multicast_v4(*group.ip(), iface).ok();
+		res?;
+		stream.flush()?;
+		self.counters.finish();
+		Ok(self.stats())
+	}
+
+	async fn receive_multicast_blocks(
+		&self,
+		stream: &mut (impl Write + Seek),
+		mcast: &tokio::net::UdpSocket,
+		server_ip: IpAddr,
+		mut master: bool,
+	) -> Result<()> {
+		let blocksize = self.opt_blocksize() as usize;
+		let mut data_buf: Vec<u8> = vec![0; 4 + blocksize];
+		let mut ctrl_buf: [u8; 512] = [0; 512];
+
+		/* received[n - 1] tells whether block n is already written */
+		let mut received: Vec<bool> = Vec::new();
+		/* all blocks up to this one have been received */
+		let mut complete_up_to: u16 = 0;
+		let mut last_block: Option<u16> = None;
+
+		let mut last_activity = Instant::now();
+		let mut attempts: u8 = 0;
+
+		if master {
+			self.send_packet(&pkt::MutableTftpAck::new(0)).await?;
+		}
+
+		loop {
+			if self.cancelled() {
+				return Err(ConnectionError::Cancelled);
+			}
+
+			match tokio::time::timeout(POLL_INTERVAL, mcast.recv_from(&mut data_buf[..])).await {
+				Ok(Ok((len, from))) if from.ip() == server_ip => {
+					if let Ok(data) = pkt::TftpData::try_from(&data_buf[..len]) {
+						let blocknum = data.blocknum();
+						let idx = blocknum as usize;
+						last_activity = Instant::now();
+						attempts = 0;
+
+						if blocknum == 0 || data.data_len() > blocksize {
+							continue;
+						}
+						if received.len() < idx {
+							received.resize(idx, false);
+						}
+
+						if !received[idx - 1] {
+							stream.seek(SeekFrom::Start((idx as u64 - 1) * blocksize as u64))?;
+							stream.write_all(data.data())?;
+							received[idx - 1] = true;
+							self.block_done(data.data_len());
+							if data.data_len() < blocksize {
+								last_block = Some(blocknum);
+							}
+
+							let prev = complete_up_to;
+							while received.get(complete_up_to as usize).copied().unwrap_or(false) {
+								complete_up_to += 1;
+							}
+							if master && complete_up_to != prev {
+								self.send_packet(&pkt::MutableTftpAck::new(complete_up_to)).await?;
+							}
+						} else {
+							self.counters.duplicate();
+							if master && blocknum == complete_up_to {
+								/* The server retransmitted, i.e. our ACK got lost */
+								self.send_packet(&pkt::MutableTftpAck::new(complete_up_to)).await?;
+							}
+						}
+					}
+				},
+				Ok(Ok(_)) | Err(_) => (),
+				Ok(Err(e)) => return Err(e.into()),
+			}
+
+			match self.receive_packet_within(&mut ctrl_buf, Duration::ZERO).await {
+				Ok(pkt::TftpPacket::OAck(oack)) => {
+					let mc = oack.options()?
+						.into_iter()
+						.find(|(key, _)| key.eq_ignore_ascii_case(consts::OPT_MULTICAST_IDENT))
+						.map(|(_, val)| val.parse::<MulticastParams>());
+					if let Some(Ok(mc)) = mc {
+						if mc.master && !master {
+							debug!("became master client of the multicast session");
+						}
+						master = mc.master;
+						last_activity = Instant::now();
+						attempts = 0;
+						if master {
+							self.send_packet(&pkt::MutableTftpAck::new(complete_up_to)).await?;
+						}
+					}
+				},
+				Ok(pkt::TftpPacket::Err(error)) => return Err(ConnectionError::PeerError(error.into())),
+				Ok(_) | Err(ConnectionError::Timeout) | Err(ConnectionError::UnknownTid) => (),
+				Err(e) => return Err(e),
+			}
+
+			if let Some(last) = last_block {
+				if complete_up_to == last {
+					/* Tells the server we are done; the master did so with its last ACK */
+					if !master {
+						self.send_packet(&pkt::MutableTftpAck::new(last)).await?;
+					}
+					debug!("received file in {} blocks via multicast", last);
+					return Ok(());
+				}
+			}
+
+			/* A client that isn't master only waits; the server's OACK may take a while
+			 * if other clients are served first, but data keeps arriving meanwhile. */
+			if last_activity.elapsed() >= self.opt_timeout() {
+				self.counters.timeout();
+				if attempts >= consts::DEFAULT_RETRANSMIT_ATTEMPTS {
+					return Err(ConnectionError::Timeout);
+				}
+				attempts += 1;
+				last_activity = Instant::now();
+				if master {
+					debug!("timeout in multicast session, ACKing block {} again", complete_up_to);
+					self.counters.retransmission();
+					self.send_packet(&pkt::MutableTftpAck::new(complete_up_to)).await?;
+				}
+			}
+		}
+	}
+}
+
+///
+/// Returns the options of a multicast OACK with the multicast option replaced by the
+/// one for the client.
+///
+pub fn oack_options(options: &[TftpOption], group: SocketAddrV4, master: bool) -> Vec<TftpOption> {
+	let mut res: Vec<TftpOption> = options
+		.iter()
+		.filter(|e| !matches!(e, TftpOption::Multicast(_)))
+		.cloned()
+		.collect();
+	res.push(TftpOption::Multicast(Some(MulticastParams { group: Some(group), master })));
+	res
+}
@@ -1,39 +1,158 @@
 use std::time::Duration;
 use std::collections::HashMap;
+use std::fmt::Debug;
+use std::sync::Arc;
 
-use crate::tftp::consts;
+use crate::tftp::{consts, RequestKind};
 use crate::tftp::error::OptionError;
+pub use crate::tftp::multicast::MulticastParams;
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum TftpOptionKind {
 	Blocksize,
 	Timeout,
+	UTimeout,
 	TransferSize,
+	WindowSize,
+	Multicast,
+	Custom,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum TftpOption {
 	Blocksize(u16),
 	Timeout(Duration),
+	/// Like `Timeout` but in microseconds, for sub-second timeouts. Not standardized,
+	/// but widely supported (e.g. tftp-hpa).
+	UTimeout(Duration),
 	TransferSize(u32),
+	/// RFC 7440, the number of DATA packets sent before waiting for an ACK.
+	WindowSize(u16),
+	/// RFC 2090; requests carry no value, the OACK carries group and master status.
+	Multicast(Option<MulticastParams>),
+	/// Any other option as name and value, e.g. a vendor option.
+	Custom(String, String),
 }
 impl TftpOption {
 	pub fn kind(&self) -> TftpOptionKind {
 		match self {
 			Self::Blocksize(_) => TftpOptionKind::Blocksize,
 			Self::Timeout(_) => TftpOptionKind::Timeout,
+			Self::UTimeout(_) => TftpOptionKind::UTimeout,
 			Self::TransferSize(_) => TftpOptionKind::TransferSize,
+			Self::WindowSize(_) => TftpOptionKind::WindowSize,
+			Self::Multicast(_) => TftpOptionKind::Multicast,
+			Self::Custom(_, _) => TftpOptionKind::Custom,
+		}
+	}
+	pub fn name(&self) -> &str {
+		match self {
+			Self::Blocksize(_) => consts::OPT_BLOCKSIZE_IDENT,
+			Self::Timeout(_) => consts::OPT_TIMEOUT_IDENT,
+			Self::UTimeout(_) => consts::OPT_UTIMEOUT_IDENT,
+			Self::TransferSize(_) => consts::OPT_TRANSFERSIZE_IDENT,
+			Self::WindowSize(_) => consts::OPT_WINDOWSIZE_IDENT,
+			Self::Multicast(_) => consts::OPT_MULTICAST_IDENT,
+			Self::Custom(name, _) => name,
 		}
 	}
-	pub fn as_str_tuple(&self) -> (&'static str, String) {
+	pub fn as_str_tuple(&self) -> (&str, String) {
 		match self {
-			Self::Blocksize(bs) => (consts::OPT_BLOCKSIZE_IDENT, bs.to_string()),
-			Self::Timeout(t) => (consts::OPT_TIMEOUT_IDENT, t.as_secs().to_string()),
-			Self::TransferSize(ts) => (consts::OPT_TRANSFERSIZE_IDENT, ts.to_string()),
+			Self::Blocksize(bs) => (self.name(), bs.to_string()),
+			Self::Timeout(t) => (self.name(), t.as_secs().to_string()),
+			Self::UTimeout(t) => (self.name(), t.as_micros().to_string()),
+			Self::TransferSize(ts) => (self.name(), ts.to_string()),
+			Self::WindowSize(ws) => (self.name(), ws.to_string()),
+			Self::Multicast(mc) => (self.name(), mc.map_or(String::new(), |e| e.to_string())),
+			Self::Custom(name, value) => (name, value.clone()),
 		}
 	}
 }
 
+/// Whether the option is one of those implemented by this crate.
+pub fn is_builtin_option(name: &str) -> bool {
+	[
+		consts::OPT_BLOCKSIZE_IDENT,
+		consts::OPT_TIMEOUT_IDENT,
+		consts::OPT_UTIMEOUT_IDENT,
+		consts::OPT_TRANSFERSIZE_IDENT,
+		consts::OPT_WINDOWSIZE_IDENT,
+		consts::OPT_MULTICAST_IDENT,
+	]
+		.iter()
+		.any(|ident| ident.eq_ignore_ascii_case(name))
+}
+
+///
+/// Handles an option this crate doesn't implement itself, e.g. a vendor option
+/// of a bootloader. Handlers are registered in an `OptionRegistry`.
+/// 
+pub trait OptionHandler: Send + Sync {
+	/// The name of the option, matched case-insensitively.
+	fn name(&self) -> &str;
+
+	/// Parses and validates the value requested by a client (on the server) or
+	/// acknowledged by a server (on the client). Returns the value to acknowledge,
+	/// or `None` if the value is unacceptable.
+	fn negotiate(&self, value: &str, req_kind: RequestKind) -> Option<String>;
+
+	/// Applies the acknowledged value to the options of the transfer.
+	fn apply(&self, _value: &str, _options: &mut TftpOptions) {}
+}
+
+///
+/// Registered handlers for custom options. Handlers for builtin options (blksize,
+/// timeout, utimeout, tsize, windowsize, multicast) are never consulted, those are always handled
+/// by the crate itself.
+/// 
+#[derive(Clone, Default)]
+pub struct OptionRegistry {
+	handlers: Vec<Arc<dyn OptionHandler>>,
+}
+impl OptionRegistry {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Registers a handler, replacing any handler for the same option.
+	pub fn register(&mut self, handler: impl OptionHandler + 'static) {
+		self.handlers.retain(|e| !e.name().eq_ignore_ascii_case(handler.name()));
+		self.handlers.push(Arc::new(handler));
+	}
+
+	pub fn handler(&self, name: &str) -> Option<&dyn OptionHandler> {
+		self.handlers
+			.iter()
+			.find(|e| e.name().eq_ignore_ascii_case(name))
+			.map(|e| e.as_ref())
+	}
+
+	/// Lets the handlers of all acknowledged custom options apply their values.
+	pub fn apply(&self, options: &mut TftpOptions) {
+		let custom = options.custom.clone();
+		for (name, value) in custom.iter() {
+			if let Some(handler) = self.handler(name) {
+				handler.apply(value, options);
+			}
+		}
+	}
+}
+impl Debug for OptionRegistry {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_list().entries(self.handlers.iter().map(|e| e.name())).finish()
+	}
+}
+
+///
+/// Looks up an option by name. Option names are case-insensitive (RFC 2347).
+/// 
+pub fn get_option<'a>(raw_opts: &HashMap<&str, &'a str>, ident: &str) -> Option<&'a str> {
+	raw_opts
+		.iter()
+		.find(|(key, _)| key.eq_ignore_ascii_case(ident))
+		.map(|(_, val)| *val)
+}
+
 ///
 /// This skips unknown options but returns an error in case a known option
 /// has an invalid value.
@@ -41,31 +160,216 @@ impl TftpOption {
 pub fn parse_tftp_options(raw_opts: HashMap<&str, &str>) -> Result<Vec<TftpOption>, OptionError> {
 	let mut res: Vec<TftpOption> = Vec::with_capacity(3);
 
-	if let Some(val) = raw_opts.get(consts::OPT_BLOCKSIZE_IDENT) {
-		if let Ok(size) = u16::from_str_radix(*val, 10) {
+	if let Some(val) = get_option(&raw_opts, consts::OPT_BLOCKSIZE_IDENT) {
+		if let Ok(size) = val.parse::<u16>() {
 			res.push(TftpOption::Blocksize(size));
 		} else { return Err(OptionError::InvalidOption); }
 	}
 
-	if let Some(val) = raw_opts.get(consts::OPT_TIMEOUT_IDENT) {
-		if let Ok(timeout) = u8::from_str_radix(*val, 10) {
+	if let Some(val) = get_option(&raw_opts, consts::OPT_TIMEOUT_IDENT) {
+		if let Ok(timeout) = val.parse::<u8>() {
 			res.push(TftpOption::Timeout(Duration::from_secs(timeout as u64)));
 		} else { return Err(OptionError::InvalidOption); }
 	}
 
-	if let Some(val) = raw_opts.get(consts::OPT_TRANSFERSIZE_IDENT) {
-		if let Ok(tf_size) = u32::from_str_radix(*val, 10) {
+	/* Keep after timeout, so it takes precedence when both are set */
+	if let Some(val) = get_option(&raw_opts, consts::OPT_UTIMEOUT_IDENT) {
+		if let Ok(timeout) = val.parse::<u32>() {
+			res.push(TftpOption::UTimeout(Duration::from_micros(timeout as u64)));
+		} else { return Err(OptionError::InvalidOption); }
+	}
+
+	if let Some(val) = get_option(&raw_opts, consts::OPT_TRANSFERSIZE_IDENT) {
+		if let Ok(tf_size) = val.parse::<u32>() {
 			res.push(TftpOption::TransferSize(tf_size));
 		} else { return Err(OptionError::InvalidOption); }
 	}
 
+	if let Some(val) = get_option(&raw_opts, consts::OPT_WINDOWSIZE_IDENT) {
+		match val.parse::<u16>() {
+			Ok(ws) if ws >= 1 => res.push(TftpOption::WindowSize(ws)),
+			_ => return Err(OptionError::InvalidOption),
+		}
+	}
+
+	if let Some(val) = get_option(&raw_opts, consts::OPT_MULTICAST_IDENT) {
+		if val.is_empty() {
+			res.push(TftpOption::Multicast(None));
+		} else if let Ok(mc) = val.parse::<MulticastParams>() {
+			res.push(TftpOption::Multicast(Some(mc)));
+		} else { return Err(OptionError::InvalidOption); }
+	}
+
 	Ok(res)
 }
 
+///
+/// Checks the options acknowledged by the server against the ones we requested and
+/// returns the negotiated options. The server may only acknowledge options we asked
+/// for (RFC 2347), must not raise the blksize (RFC 2348) or windowsize (RFC 7440) and
+/// has to echo the timeout as well as the tsize of a WRQ unchanged (RFC 2349). Custom options are checked by
+/// their registered handler, if there is one.
+/// 
+pub fn validate_oack(
+	requested: &[TftpOption],
+	raw_opts: HashMap<&str, &str>,
+	req_kind: RequestKind,
+	registry: &OptionRegistry
+) -> Result<Vec<TftpOption>, OptionError> {
+	let mut custom: Vec<TftpOption> = Vec::new();
+	for (key, value) in raw_opts.iter() {
+		let Some(req) = requested.iter().find(|e| e.name().eq_ignore_ascii_case(key)) else {
+			return Err(OptionError::Unrequested);
+		};
+		if req.kind() == TftpOptionKind::Custom {
+			let value = match registry.handler(key) {
+				Some(handler) => handler.negotiate(value, req_kind).ok_or(OptionError::InvalidOption)?,
+				None => value.to_string(),
+			};
+			custom.push(TftpOption::Custom(req.name().to_string(), value));
+		}
+	}
+
+	let mut acked = parse_tftp_options(raw_opts)?;
+	for opt in acked.iter() {
+		let Some(req) = requested.iter().find(|e| e.kind() == opt.kind()) else {
+			return Err(OptionError::Unrequested);
+		};
+		let valid = match (opt, req) {
+			(TftpOption::Blocksize(bs), TftpOption::Blocksize(req_bs)) => *bs >= 8 && bs <= req_bs,
+			(TftpOption::Timeout(t), TftpOption::Timeout(req_t)) => t == req_t,
+			(TftpOption::UTimeout(t), TftpOption::UTimeout(req_t)) => t == req_t,
+			(TftpOption::TransferSize(ts), TftpOption::TransferSize(req_ts)) => {
+				req_kind == RequestKind::Rrq || ts == req_ts
+			},
+			(TftpOption::WindowSize(ws), TftpOption::WindowSize(req_ws)) => ws <= req_ws,
+			(TftpOption::Multicast(mc), TftpOption::Multicast(_)) => mc.is_some(),
+			_ => false,
+		};
+		if !valid {
+			return Err(OptionError::InvalidOption);
+		}
+	}
+
+	acked.extend(custom);
+	Ok(acked)
+}
+
+///
+/// Server-side rules for answering the options requested by a client.
+/// 
+/// Options we don't know or whose values are unacceptable are left out of the OACK
+/// instead of failing the request, the client then falls back to the defaults
+/// (RFC 2347).
+/// 
+/// With `mtu_clamp` set, the blksize is additionally lowered to what fits into the
+/// MTU towards the client, so DATA packets don't get fragmented. Windowsizes above
+/// `max_windowsize` are lowered to it.
+/// 
+/// Other options are acknowledged if a handler in `registry` accepts them.
+/// 
+#[derive(Debug, Clone)]
+pub struct NegotiationPolicy {
+	pub min_blocksize: u16,
+	pub max_blocksize: u16,
+	pub mtu_clamp: bool,
+	pub max_windowsize: u16,
+	pub registry: OptionRegistry,
+}
+impl Default for NegotiationPolicy {
+	fn default() -> Self {
+		Self {
+			min_blocksize: consts::MIN_BLOCK_SIZE,
+			max_blocksize: consts::MAX_BLOCK_SIZE,
+			mtu_clamp: true,
+			max_windowsize: consts::DEFAULT_MAX_WINDOW_SIZE,
+			registry: OptionRegistry::default(),
+		}
+	}
+}
+
+/// Result of `NegotiationPolicy::negotiate`.
+#[derive(Debug, Clone, Default)]
+pub struct Negotiation {
+	/// The options to acknowledge.
+	pub options: Vec<TftpOption>,
+	/// Requested options without a handler, as name and value. These are not
+	/// acknowledged.
+	pub unknown: Vec<(String, String)>,
+}
+
+impl NegotiationPolicy {
+	/// `mtu_blocksize` is the largest blksize that fits into the MTU of the interface
+	/// the client is reached through, if known.
+	pub fn negotiate(
+		&self,
+		raw_opts: &HashMap<&str, &str>,
+		mtu_blocksize: Option<u16>,
+		req_kind: RequestKind
+	) -> Negotiation {
+		let mut res: Vec<TftpOption> = Vec::with_capacity(3);
+		let mut unknown: Vec<(String, String)> = Vec::new();
+
+		/* We may answer with a smaller blksize than requested, but never with a larger one */
+		if let Some(Ok(requested)) = get_option(raw_opts, consts::OPT_BLOCKSIZE_IDENT).map(str::parse::<u64>) {
+			let max = match mtu_blocksize {
+				Some(mtu) if self.mtu_clamp => mtu.min(self.max_blocksize),
+				_ => self.max_blocksize,
+			};
+			let size = requested.min(max as u64) as u16;
+			if requested >= consts::MIN_BLOCK_SIZE as u64 && size >= self.min_blocksize {
+				res.push(TftpOption::Blocksize(size));
+			}
+		}
+
+		if let Some(Ok(timeout)) = get_option(raw_opts, consts::OPT_TIMEOUT_IDENT).map(str::parse::<u8>) {
+			if timeout >= 1 {
+				res.push(TftpOption::Timeout(Duration::from_secs(timeout as u64)));
+			}
+		}
+
+		if let Some(Ok(timeout)) = get_option(raw_opts, consts::OPT_UTIMEOUT_IDENT).map(str::parse::<u32>) {
+			if (consts::MIN_UTIMEOUT_USECS..=consts::MAX_UTIMEOUT_USECS).contains(&timeout) {
+				res.push(TftpOption::UTimeout(Duration::from_micros(timeout as u64)));
+			}
+		}
+
+		if let Some(Ok(tf_size)) = get_option(raw_opts, consts::OPT_TRANSFERSIZE_IDENT).map(str::parse::<u32>) {
+			res.push(TftpOption::TransferSize(tf_size));
+		}
+
+		if let Some(Ok(windowsize)) = get_option(raw_opts, consts::OPT_WINDOWSIZE_IDENT).map(str::parse::<u16>) {
+			if windowsize >= 1 {
+				res.push(TftpOption::WindowSize(windowsize.min(self.max_windowsize.max(1))));
+			}
+		}
+
+		for (key, value) in raw_opts.iter().filter(|(key, _)| !is_builtin_option(key)) {
+			match self.registry.handler(key) {
+				Some(handler) => {
+					if let Some(acked) = handler.negotiate(value, req_kind) {
+						res.push(TftpOption::Custom(handler.name().to_string(), acked));
+					}
+				},
+				None => unknown.push((key.to_string(), value.to_string())),
+			}
+		}
+
+		Negotiation { options: res, unknown }
+	}
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct TftpOptions {
 	pub blocksize: u16,
 	pub timeout: Duration,
-	pub transfer_size: u32
+	pub transfer_size: u32,
+	pub windowsize: u16,
+	pub multicast: Option<MulticastParams>,
+	/// Acknowledged custom options as name and value.
+	pub custom: Vec<(String, String)>,
+	/// Options the peer requested that nobody handles, as name and value.
+	pub unknown: Vec<(String, String)>,
 }
 impl Default for TftpOptions {
 	fn default() -> Self {
@@ -73,6 +377,18 @@ impl Default for TftpOptions {
 			blocksize: consts::DEFAULT_BLOCK_SIZE, 
 			timeout: Duration::from_secs(consts::DEFAULT_TIMEOUT_SECS as u64), 
 			transfer_size: 0,
+			windowsize: 1,
+			multicast: None,
+			custom: Vec::new(),
+			unknown: Vec::new(),
 		}
 	}
+}
+impl TftpOptions {
+	pub fn custom_option(&self, name: &str) -> Option<&str> {
+		self.custom
+			.iter()
+			.find(|(key, _)| key.eq_ignore_ascii_case(name))
+			.map(|(_, value)| value.as_str())
+	}
 }
\ No newline at end of file
@@ -21,6 +21,12 @@ pub struct TftpReqBuilder<'a, 'b> {
 
 	filename: &'b str,
 	options: Option<&'b [TftpOption]>,
+	raw_options: Vec<(&'b str, &'b str)>,
+}
+impl<'a, 'b> Default for TftpReqBuilder<'a, 'b> {
+	fn default() -> Self {
+		Self::new()
+	}
 }
 impl<'a, 'b> TftpReqBuilder<'a, 'b> {
 	pub fn new() -> Self {
@@ -29,7 +35,8 @@ impl<'a, 'b> TftpReqBuilder<'a, 'b> {
 			kind: RequestKind::Rrq,
 			mode: Mode::Octet,
 			filename: "",
-			options: None
+			options: None,
+			raw_options: Vec::new(),
 		}
 	}
 
@@ -62,6 +69,24 @@ impl<'a, 'b> TftpReqBuilder<'a, 'b> {
 		self.options = Some(options);
 		self
 	}
+	/// Adds an arbitrary option, e.g. one that isn't known to this crate.
+	#[inline] pub fn raw_option(mut self, name: &'b str, value: &'b str) -> Self {
+		self.raw_options.push((name, value));
+		self
+	}
+
+	fn encoded_len(&self) -> usize {
+		let options: usize = self.options
+			.unwrap_or(&[])
+			.iter()
+			.map(|opt| {
+				let tuple = opt.as_str_tuple();
+				tuple.0.len() + tuple.1.len() + 2
+			})
+			.chain(self.raw_options.iter().map(|(name, value)| name.len() + value.len() + 2))
+			.sum();
+		2 + self.filename.len() + 1 + self.mode.as_str().len() + 1 + options
+	}
 
 	fn write_to_buf(&mut self, buf: &mut [u8]) -> usize {
 		buf[0..=1].copy_from_slice((self.kind as u16).to_be_bytes().as_slice());
@@ -82,6 +107,12 @@ impl<'a, 'b> TftpReqBuilder<'a, 'b> {
 				written += buf_ref.write(&[ 0 ]).unwrap_or(0);
 			}
 		}
+		for (name, value) in self.raw_options.iter() {
+			written += buf_ref.write(name.as_bytes()).unwrap_or(0);
+			written += buf_ref.write(&[ 0 ]).unwrap_or(0);
+			written += buf_ref.write(value.as_bytes()).unwrap_or(0);
+			written += buf_ref.write(&[ 0 ]).unwrap_or(0);
+		}
 
 		written
 	}
@@ -94,7 +125,7 @@ impl<'a, 'b> TftpReqBuilder<'a, 'b> {
 				TftpReq::from_borrowed(buf)
 			},
 			None => {
-				let mut buf = vec![0; 64];
+				let mut buf = vec![0; self.encoded_len()];
 				let len = self.write_to_buf(&mut buf[..]);
 				buf.truncate(len);
 
@@ -108,6 +139,11 @@ pub struct TftpOAckBuilder<'a> {
 	buf: Option<&'a mut [u8]>,
 	options: Vec<TftpOption>,
 }
+impl<'a> Default for TftpOAckBuilder<'a> {
+	fn default() -> Self {
+		Self::new()
+	}
+}
 impl<'a> TftpOAckBuilder<'a> {
 	pub fn new() -> Self {
 		Self {
@@ -132,10 +168,25 @@ impl<'a> TftpOAckBuilder<'a> {
 		self
 	}
 	#[inline] pub fn options(mut self, options: &[TftpOption]) -> Self {
-		self.options.extend(options);
+		self.options.extend_from_slice(options);
+		self
+	}
+	/// Adds an arbitrary option, e.g. one that isn't known to this crate.
+	#[inline] pub fn raw_option(mut self, name: &str, value: &str) -> Self {
+		self.options.push(TftpOption::Custom(name.to_string(), value.to_string()));
 		self
 	}
 
+	fn encoded_len(&self) -> usize {
+		2 + self.options
+			.iter()
+			.map(|opt| {
+				let tuple = opt.as_str_tuple();
+				tuple.0.len() + tuple.1.len() + 2
+			})
+			.sum::<usize>()
+	}
+
 	fn write_to_buf(&mut self, buf: &mut [u8]) -> usize {
 		buf[0..=1].copy_from_slice(consts::OPCODE_OACK.to_be_bytes().as_slice());
 
@@ -160,7 +211,7 @@ impl<'a> TftpOAckBuilder<'a> {
 				TftpOAck::from_borrowed(buf)
 			},
 			None => {
-				let mut buf = vec![0; 64];
+				let mut buf = vec![0; self.encoded_len()];
 				let len = self.write_to_buf(&mut buf[..]);
 				buf.truncate(len);
 
@@ -175,6 +226,11 @@ pub struct TftpErrorBuilder<'a> {
 	code: ErrorCode,
 	msg: Option<&'a str>,
 }
+impl<'a> Default for TftpErrorBuilder<'a> {
+	fn default() -> Self {
+		Self::new()
+	}
+}
 impl<'a> TftpErrorBuilder<'a> {
 	pub fn new() -> Self {
 		Self {
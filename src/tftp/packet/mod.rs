@@ -39,6 +39,23 @@ pub trait Packet {
 	fn as_bytes(&self) -> &[u8];
 }
 
+///
+/// Short description of a raw packet for tracing, e.g. `DATA block 3 (512 bytes)`.
+///
+pub fn describe(buf: &[u8]) -> String {
+	let field = |at: usize| buf.get(at..at + 2).map(|b| u16::from_be_bytes([b[0], b[1]]));
+	let arg = field(2).unwrap_or(0);
+	match field(0) {
+		Some(consts::OPCODE_RRQ) => format!("RRQ ({} bytes)", buf.len()),
+		Some(consts::OPCODE_WRQ) => format!("WRQ ({} bytes)", buf.len()),
+		Some(consts::OPCODE_DATA) => format!("DATA block {} ({} bytes)", arg, buf.len().saturating_sub(4)),
+		Some(consts::OPCODE_ACK) => format!("ACK block {}", arg),
+		Some(consts::OPCODE_ERROR) => format!("ERROR code {}", arg),
+		Some(consts::OPCODE_OACK) => format!("OACK ({} bytes)", buf.len()),
+		_ => format!("unknown packet ({} bytes)", buf.len()),
+	}
+}
+
 // ############################################################################
 // ############################################################################
 // #### IMMUTABLE PACKETS #####################################################
@@ -52,7 +69,7 @@ pub enum PacketBuf<'a> {
 impl<'a> PacketBuf<'a> {
 	pub fn inner(&'a self) -> &'a [u8] {
 		match self {
-			PacketBuf::Borrowed(b) => *b,
+			PacketBuf::Borrowed(b) => b,
 			PacketBuf::Owned(v) => &v[..]
 		}
 	} 
@@ -86,7 +103,7 @@ impl<'a> TftpReq<'a> {
 
 	fn inner(&self) -> &[u8] {
 		match self.inner {
-			PacketBuf::Borrowed(ref b) => *b,
+			PacketBuf::Borrowed(b) => b,
 			PacketBuf::Owned(ref v) => &v[..],
 		}
 	}
@@ -121,18 +138,14 @@ impl<'a> TftpReq<'a> {
 
 	pub fn mode(&self) -> Result<Mode> {
 		let buf = self.inner();
-		let mut mode_pos = 0;
-		for i in 2..(buf.len() - 1) {
-			if buf[i] == 0 {
-				mode_pos = i + 1;
-				break;
-			}
-		}
+		let mode_pos = buf[2..]
+			.iter()
+			.position(|e| *e == 0)
+			.map_or(0, |i| i + 3);
 
-		Ok(CStr::from_bytes_until_nul(&buf[mode_pos..])?
+		CStr::from_bytes_until_nul(&buf[mode_pos..])?
 			.to_str()?
-			.parse()?
-		)
+			.parse()
 	}
 
 	pub fn options(&self) -> Result<HashMap<&str, &str>> {
@@ -196,7 +209,7 @@ impl <'a> TftpData<'a> {
 
 	fn inner(&self) -> &[u8] {
 		match self.inner {
-			PacketBuf::Borrowed(ref b) => *b,
+			PacketBuf::Borrowed(b) => b,
 			PacketBuf::Owned(ref v) => &v[..],
 		}
 	}
@@ -258,7 +271,7 @@ impl<'a> TftpAck<'a> {
 	
 	fn inner(&self) -> &[u8] {
 		match self.inner {
-			PacketBuf::Borrowed(ref b) => *b,
+			PacketBuf::Borrowed(b) => b,
 			PacketBuf::Owned(ref v) => &v[..],
 		}
 	}
@@ -317,7 +330,7 @@ impl<'a> TftpOAck<'a> {
 
 	fn inner(&self) -> &[u8] {
 		match self.inner {
-			PacketBuf::Borrowed(ref b) => *b,
+			PacketBuf::Borrowed(b) => b,
 			PacketBuf::Owned(ref v) => &v[..],
 		}
 	}
@@ -392,28 +405,33 @@ impl<'a> TftpError<'a> {
 
 	fn inner(&self) -> &[u8] {
 		match self.inner {
-			PacketBuf::Borrowed(ref b) => *b,
+			PacketBuf::Borrowed(b) => b,
 			PacketBuf::Owned(ref v) => &v[..],
 		}
 	}
 
 	fn check_from_slice(buf: &'a [u8]) -> Result<()> {
-		if buf.len() < 6 {
+		if buf.len() < 5 {
 			return Err(ParseError::UnexpectedEof);
 		}
-		if u16::from_be_bytes([ buf[0], buf[1] ]) != consts::OPCODE_OACK {
+		if u16::from_be_bytes([ buf[0], buf[1] ]) != consts::OPCODE_ERROR {
 			return Err(ParseError::UnexpectedOpcode);
 		}
 		Ok(())
 	}
 
+	/// Error codes we don't know are reported as `ErrorCode::NotDefined`.
 	pub fn error_code(&self) -> ErrorCode {
 		let buf = self.inner();
-		ErrorCode::try_from(u16::from_be_bytes([ buf[2], buf[3] ])).unwrap()
+		ErrorCode::try_from(u16::from_be_bytes([ buf[2], buf[3] ])).unwrap_or(ErrorCode::NotDefined)
 	}
 
+	/// Returns the error message up to the null terminator, or an empty string in
+	/// case the peer sent garbage.
 	pub fn error_msg(&'a self) -> &'a str {
-		std::str::from_utf8(&self.inner()[4..]).unwrap()
+		let msg = &self.inner()[4..];
+		let end = msg.iter().position(|e| *e == 0).unwrap_or(msg.len());
+		std::str::from_utf8(&msg[..end]).unwrap_or("")
 	}
 }
 impl<'a> Packet for TftpError<'a> {
@@ -456,12 +474,16 @@ impl<'a> TftpPacket<'a> {
 	}
 
 	pub fn try_from_buf(buf: &'a [u8]) -> Result<Self> {
+		if buf.len() < 2 {
+			return Err(ParseError::UnexpectedEof);
+		}
 		Ok(
 			match u16::from_be_bytes([ buf[0], buf[1] ]) {
 				consts::OPCODE_RRQ | consts::OPCODE_WRQ => Self::Req(TftpReq::try_from(buf)?),
 				consts::OPCODE_ACK => Self::Ack(TftpAck::try_from(buf)?),
 				consts::OPCODE_OACK => Self::OAck(TftpOAck::try_from(buf)?),
 				consts::OPCODE_DATA => Self::Data(TftpData::try_from(buf)?),
+				consts::OPCODE_ERROR => Self::Err(TftpError::try_from(buf)?),
 				x => return Err(ParseError::InvalidOpcode(x)),
 			}
 		)
@@ -481,7 +503,7 @@ pub enum MutablePacketBuf<'a> {
 impl<'a> MutablePacketBuf<'a> {
 	pub fn inner(&'a mut self) -> &'a mut [u8] {
 		match self {
-			MutablePacketBuf::Borrowed(b) => *b,
+			MutablePacketBuf::Borrowed(b) => b,
 			MutablePacketBuf::Owned(v) => &mut v[..]
 		}
 	}
@@ -495,7 +517,7 @@ impl<'a> MutablePacketBuf<'a> {
 impl AsRef<[u8]> for MutablePacketBuf<'_> {
 	fn as_ref(&self) -> &[u8] {
 		match self {
-			MutablePacketBuf::Borrowed(b) => *b,
+			MutablePacketBuf::Borrowed(b) => b,
 			MutablePacketBuf::Owned(v) => &v[..]
 		}
 	}
@@ -503,7 +525,7 @@ impl AsRef<[u8]> for MutablePacketBuf<'_> {
 impl AsMut<[u8]> for MutablePacketBuf<'_> {
 	fn as_mut(&mut self) -> &mut [u8] {
 		match self {
-			MutablePacketBuf::Borrowed(b) => *b,
+			MutablePacketBuf::Borrowed(b) => b,
 			MutablePacketBuf::Owned(v) => &mut v[..]
 		}
 	}
@@ -516,13 +538,13 @@ pub struct MutableTftpData<'a> {
 impl<'a> MutableTftpData<'a> {
 	fn inner(&self) -> &[u8] {
 		match self.buf {
-			MutablePacketBuf::Borrowed(ref b) => *b,
+			MutablePacketBuf::Borrowed(ref b) => b,
 			MutablePacketBuf::Owned(ref v) => &v[..],
 		}
 	}
 	fn inner_mut(&mut self) -> &mut [u8] {
 		match self.buf {
-			MutablePacketBuf::Borrowed(ref mut b) => *b,
+			MutablePacketBuf::Borrowed(ref mut b) => b,
 			MutablePacketBuf::Owned(ref mut v) => &mut v[..],
 		}
 	}
@@ -583,6 +605,7 @@ impl<'a> MutableTftpData<'a> {
 		u16::from_be_bytes([ buf[2], buf[3] ])
 	}
 	pub fn len(&self) -> usize { self.len }
+	pub fn is_empty(&self) -> bool { self.len == 0 }
 }
 impl<'a> Packet for MutableTftpData<'a> {
 	fn packet_kind(&self) -> PacketKind {
@@ -637,7 +660,7 @@ impl<'a> MutableTftpError<'a> {
 
 		buf[0..=1].copy_from_slice(&consts::OPCODE_ERROR.to_be_bytes()[..]);
 		buf[2..=3].copy_from_slice(&(err_code as u16).to_be_bytes()[..]);
-		if err_msg.len() > 0 && err_msg.is_ascii() {
+		if !err_msg.is_empty() && err_msg.is_ascii() {
 			let max_len = buf.len() - 1;
 			let copied = utils::copy(err_msg.as_bytes(), &mut buf[4..max_len]);
 			len += copied;
@@ -660,6 +683,7 @@ impl<'a> MutableTftpError<'a> {
 	}
 
 	pub fn len(&self) -> usize { self.buf.len() }
+	pub fn is_empty(&self) -> bool { self.buf.is_empty() }
 	pub fn as_bytes(&self) -> &[u8] { &self.buf[..self.data_len] }
 }
 
@@ -0,0 +1,153 @@
+use std::fmt::Display;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::tftp::checksum::Checksum;
+use crate::tftp::options::TftpOptions;
+
+///
+/// Statistics of a transfer, returned once it is finished.
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct TransferStats {
+	pub peer: SocketAddr,
+	/// Payload bytes, i.e. the size of the file.
+	pub bytes: u64,
+	pub blocks: u64,
+	/// From the request until the last block was acknowledged.
+	pub duration: Duration,
+	/// Packets sent again because the peer didn't reply in time.
+	pub retransmissions: u32,
+	/// Blocks or ACKs the peer sent more than once.
+	pub duplicates: u32,
+	/// How often we waited for the peer in vain.
+	pub timeouts: u32,
+	pub options: TftpOptions,
+	/// How often the transfer was started until it succeeded, see `RetryPolicy`.
+	pub attempts: u32,
+	/// Of the data transferred, if asked for.
+	pub digest: Option<Checksum>,
+}
+
+impl TransferStats {
+	/// Bytes per second.
+	pub fn throughput(&self) -> f64 {
+		match self.duration.as_secs_f64() {
+			secs if secs > 0.0 => self.bytes as f64 / secs,
+			_ => 0.0,
+		}
+	}
+}
+
+impl Display for TransferStats {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(
+			f,
+			"{} bytes in {} blocks, {:.3}s ({:.1} KiB/s), {} retransmissions, {} duplicates, {} timeouts (blksize {})",
+			self.bytes,
+			self.blocks,
+			self.duration.as_secs_f64(),
+			self.throughput() / 1024.0,
+			self.retransmissions,
+			self.duplicates,
+			self.timeouts,
+			self.options.blocksize,
+		)?;
+		if self.options.windowsize > 1 {
+			write!(f, " (windowsize {})", self.options.windowsize)?;
+		}
+		if self.attempts > 1 {
+			write!(f, " after {} attempts", self.attempts)?;
+		}
+		Ok(())
+	}
+}
+
+///
+/// Gets told about the progress of a transfer, after every block.
+///
+/// Closures taking the bytes transferred so far and the expected total can be used
+/// as observers.
+///
+pub trait ProgressObserver: Send + Sync {
+	/// `total` is the size of the file, if known; for downloads only if the server
+	/// told us via tsize.
+	fn progress(&self, transferred: u64, total: Option<u64>);
+}
+
+impl<F: Fn(u64, Option<u64>) + Send + Sync> ProgressObserver for F {
+	fn progress(&self, transferred: u64, total: Option<u64>) {
+		self(transferred, total)
+	}
+}
+
+///
+/// Counters updated while a transfer is running. Atomics, since the connection is
+/// only borrowed immutably while transferring.
+///
+#[derive(Debug)]
+pub(crate) struct TransferCounters {
+	started: Instant,
+	/// Microseconds from `started` until the transfer finished, 0 while running.
+	finished: AtomicU64,
+	bytes: AtomicU64,
+	blocks: AtomicU64,
+	retransmissions: AtomicU32,
+	duplicates: AtomicU32,
+	timeouts: AtomicU32,
+}
+
+impl TransferCounters {
+	pub fn new() -> Self {
+		Self {
+			started: Instant::now(),
+			finished: AtomicU64::new(0),
+			bytes: AtomicU64::new(0),
+			blocks: AtomicU64::new(0),
+			retransmissions: AtomicU32::new(0),
+			duplicates: AtomicU32::new(0),
+			timeouts: AtomicU32::new(0),
+		}
+	}
+
+	/// Returns the bytes transferred so far.
+	pub fn block(&self, len: usize) -> u64 {
+		self.blocks.fetch_add(1, Ordering::Relaxed);
+		self.bytes.fetch_add(len as u64, Ordering::Relaxed) + len as u64
+	}
+	pub fn retransmission(&self) {
+		self.retransmissions.fetch_add(1, Ordering::Relaxed);
+	}
+	pub fn duplicate(&self) {
+		self.duplicates.fetch_add(1, Ordering::Relaxed);
+	}
+	pub fn timeout(&self) {
+		self.timeouts.fetch_add(1, Ordering::Relaxed);
+	}
+	/// Stops the clock, so lingering afterwards doesn't count.
+	pub fn finish(&self) {
+		let elapsed = self.started.elapsed().as_micros().max(1) as u64;
+		self.finished.compare_exchange(0, elapsed, Ordering::Relaxed, Ordering::Relaxed).ok();
+	}
+
+	pub fn stats(&self, peer: SocketAddr, options: TftpOptions) -> TransferStats {
+		let duration = match self.finished.load(Ordering::Relaxed) {
+			0 => self.started.elapsed(),
+			us => Duration::from_micros(us),
+		};
+
+		TransferStats {
+			peer,
+			bytes: self.bytes.load(Ordering::Relaxed),
+			blocks: self.blocks.load(Ordering::Relaxed),
+			duration,
+			retransmissions: self.retransmissions.load(Ordering::Relaxed),
+			duplicates: self.duplicates.load(Ordering::Relaxed),
+			timeouts: self.timeouts.load(Ordering::Relaxed),
+			options,
+			attempts: 1,
+			digest: None,
+		}
+	}
+}
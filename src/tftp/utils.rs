@@ -1,3 +1,6 @@
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+
+use socket2::SockRef;
 
 /// 
 /// Modified variant of 'copy_from_slice'.
@@ -18,4 +21,62 @@ pub fn copy<T: Copy>(src: &[T], dst: &mut [T]) -> usize{
 		)
 	}
 	len
-}
\ No newline at end of file
+}
+
+/// 
+/// Returns the MTU of the path a connected socket sends on, as known by the
+/// kernel (`IP_MTU`/`IPV6_MTU`).
+/// 
+#[cfg(target_os = "linux")]
+pub fn path_mtu(socket: SockRef<'_>) -> Option<u32> {
+	use std::os::fd::AsRawFd;
+
+	let (level, name) = match socket.local_addr().ok()?.as_socket()? {
+		SocketAddr::V4(_) => (libc::IPPROTO_IP, libc::IP_MTU),
+		SocketAddr::V6(_) => (libc::IPPROTO_IPV6, libc::IPV6_MTU),
+	};
+	let mut mtu: libc::c_int = 0;
+	let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+	let ret = unsafe {
+		libc::getsockopt(
+			socket.as_raw_fd(),
+			level,
+			name,
+			&mut mtu as *mut libc::c_int as *mut libc::c_void,
+			&mut len
+		)
+	};
+
+	(ret == 0 && mtu > 0).then_some(mtu as u32)
+}
+
+/// 
+/// Not supported on this platform.
+/// 
+#[cfg(not(target_os = "linux"))]
+pub fn path_mtu(_socket: SockRef<'_>) -> Option<u32> {
+	None
+}
+
+/// 
+/// Returns the MTU of the path towards `to` without having a connected socket at
+/// hand, e.g. before sending a request whose reply comes from another port.
+/// 
+pub fn path_mtu_to(local_addr: IpAddr, to: SocketAddr) -> Option<u32> {
+	let socket = UdpSocket::bind(SocketAddr::new(local_addr, 0)).ok()?;
+	socket.connect(to).ok()?;
+	path_mtu((&socket).into())
+}
+
+/// 
+/// Returns the largest blksize whose DATA packets fit into the given MTU
+/// without IP fragmentation.
+/// 
+pub fn blocksize_for_mtu(mtu: u32, addr: &SocketAddr) -> u16 {
+	let ip_header: u32 = match addr {
+		SocketAddr::V4(_) => 20,
+		SocketAddr::V6(_) => 40,
+	};
+	let size = mtu.saturating_sub(ip_header + 8 + 4);
+	size.min(super::consts::MAX_BLOCK_SIZE as u32) as u16
+}
@@ -0,0 +1,91 @@
+//!
+//! Multicast downloads (RFC 2090) on loopback: one server, two clients sharing its
+//! session for the same file.
+//!
+#![cfg(all(feature = "client", feature = "server"))]
+
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
+
+use tftp::client::{TftpClient, TftpRequestParameters};
+use tftp::options::TftpOption;
+use tftp::server::{MulticastConfig, TftpServer};
+use tftp::RequestKind;
+
+const GROUP: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(239, 255, 69, 1), 17580);
+const BLOCKS: usize = 400;
+
+fn scratch_dir(name: &str) -> PathBuf {
+	let dir = std::env::temp_dir().join(format!("tftp-test-{}-{}", name, std::process::id()));
+	std::fs::create_dir_all(dir.join("srv")).unwrap();
+	std::fs::create_dir_all(dir.join("cli")).unwrap();
+	dir
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn late_client_becomes_master_after_the_first_one() {
+	let dir = scratch_dir("multicast");
+	let content: Vec<u8> = (0..BLOCKS * 512 + 100).map(|i| (i % 251) as u8).collect();
+	std::fs::write(dir.join("srv/image.bin"), &content).unwrap();
+
+	let mut server = TftpServer::new((Ipv4Addr::LOCALHOST, 0).into(), dir.join("srv")).unwrap();
+	server.set_multicast(Some(MulticastConfig::new(GROUP)));
+	let server_addr = server.local_addr().unwrap();
+	let token = CancellationToken::new();
+	let server_task = tokio::spawn({
+		let token = token.clone();
+		async move { server.run(token).await }
+	});
+
+	let mut client = TftpClient::new(token.clone());
+	client.set_local_addr(Ipv4Addr::LOCALHOST.into());
+	client.add_option(&TftpOption::Multicast(None));
+
+	/* The first client is the master and slow to ACK. The second one joins a quarter of
+	 * the way in, so it misses the first blocks and only gets them as the next master. */
+	let joining = Arc::new(Notify::new());
+	let first = {
+		let joining = joining.clone();
+		let notified = AtomicBool::new(false);
+		TftpRequestParameters::new(RequestKind::Rrq, server_addr, dir.join("cli/first.bin"))
+			.remote("image.bin")
+			.progress(Arc::new(move |transferred: u64, _| {
+				if transferred >= (BLOCKS * 512 / 4) as u64 && !notified.swap(true, Ordering::Relaxed) {
+					joining.notify_one();
+				}
+				std::thread::sleep(Duration::from_millis(2));
+			}))
+	};
+	let second = TftpRequestParameters::new(RequestKind::Rrq, server_addr, dir.join("cli/second.bin"))
+		.remote("image.bin");
+
+	let (first, second) = tokio::join!(
+		async {
+			let stats = client.request(&first).await;
+			(stats, Instant::now())
+		},
+		async {
+			joining.notified().await;
+			let stats = client.request(&second).await;
+			(stats, Instant::now())
+		},
+	);
+	let ((first, first_done), (second, second_done)) = (first, second);
+	token.cancel();
+	server_task.await.unwrap().unwrap();
+
+	assert_eq!(first.unwrap().bytes, content.len() as u64);
+	assert_eq!(second.unwrap().bytes, content.len() as u64);
+	assert!(std::fs::read(dir.join("cli/first.bin")).unwrap() == content, "first download differs");
+	assert!(std::fs::read(dir.join("cli/second.bin")).unwrap() == content, "second download differs");
+	/* The blocks the second client missed were only sent once it was master */
+	assert!(second_done >= first_done, "second client finished before the first one handed over");
+
+	std::fs::remove_dir_all(&dir).ok();
+}